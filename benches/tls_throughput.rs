@@ -0,0 +1,156 @@
+//! Compares the tunnel throughput with rustls and with kernel TLS encrypting the records.
+//!
+//! Run with `cargo bench --bench tls_throughput`, kernel TLS needs the `tls` module,
+//! otherwise both rows measure rustls. Set `TLS_BENCH_MB` to change the amount sent.
+
+use std::env;
+use std::net;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+
+use simple_tunnel::cert::{self, KeyType};
+use simple_tunnel::datagram::{Rx, Tx};
+use simple_tunnel::sockets::{dial, tls, websocket};
+
+const MESSAGE_SIZE: usize = 1400;
+
+fn main() -> Result<()> {
+    let megabytes = env::var("TLS_BENCH_MB")
+        .ok()
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or(256);
+    let messages = megabytes * 1024 * 1024 / MESSAGE_SIZE;
+
+    let dir = env::temp_dir().join(format!("tls_throughput.{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+    let ca = cert::init_ca("bench CA", 1, KeyType::EcdsaP256)?;
+    ca.write(&path("ca_cert.pem"), &path("ca_key.pem"), true)?;
+    let authority = cert::Authority::load(&path("ca_cert.pem"), &path("ca_key.pem"), None)?;
+    let server = authority.issue_server(&["localhost".to_string()], 1, KeyType::EcdsaP256)?;
+    server.write(&path("cert.pem"), &path("key.pem"), true)?;
+    let certs = Arc::new(tls::CertificateFiles::load(
+        &path("cert.pem"),
+        &path("key.pem"),
+        None,
+    )?);
+
+    println!(
+        "{:<40} {:<8} {:>10} {:>10}",
+        "cipher suite", "tls", "MB/s", "seconds"
+    );
+    for (version, suite) in &[
+        ("1.3", "TLS13_AES_128_GCM_SHA256"),
+        ("1.3", "TLS13_AES_256_GCM_SHA384"),
+        ("1.2", "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256"),
+        ("1.2", "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384"),
+    ] {
+        for &kernel_tls in &[false, true] {
+            let mut policy =
+                tls::TlsPolicy::parse(&[version.to_string()], &[suite.to_string()], &[])?;
+            if kernel_tls {
+                policy.enable_kernel_tls();
+            }
+            let (seconds, kernel) = run(certs.clone(), &policy, &path("ca_cert.pem"), messages)?;
+            let mode = match (kernel_tls, kernel) {
+                (false, _) => "rustls",
+                (true, true) => "kernel",
+                (true, false) => "fallback",
+            };
+            println!(
+                "{:<40} {:<8} {:>10.1} {:>10.2}",
+                suite,
+                mode,
+                (messages * MESSAGE_SIZE) as f64 / seconds / 1e6,
+                seconds
+            );
+        }
+    }
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+/// Sends `messages` from the client to the server, returns the seconds it took
+/// and whether both ends used kernel TLS.
+fn run(
+    certs: Arc<tls::CertificateFiles>,
+    policy: &tls::TlsPolicy,
+    ca_cert_path: &str,
+    messages: usize,
+) -> Result<(f64, bool)> {
+    let auth = || websocket::BasicAuthentication {
+        username: "bench".to_string(),
+        password: "bench".to_string(),
+    };
+
+    let tcp_listener = net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = tcp_listener.local_addr()?;
+    let handshake = websocket::ServerHandshake::new(
+        websocket::Users::new(vec![auth()]),
+        websocket::HandshakeOptions {
+            path: "/".to_string(),
+            trust_forwarded: false,
+            max_auth_failures: 10,
+            required_headers: Default::default(),
+            vhost_users: Default::default(),
+            dns: Default::default(),
+            user_dns: Default::default(),
+        },
+    );
+    let listener = websocket::TlsTcpListener::new(tcp_listener, certs, policy, handshake);
+
+    let server = thread::spawn(move || -> std::io::Result<bool> {
+        let mut ws = listener.accept()?;
+        let mut buf = [0u8; 2 * MESSAGE_SIZE];
+        for _ in 0..messages {
+            ws.recv(&mut buf)?;
+        }
+        ws.send(b"done")?;
+        ws.flush()?;
+        Ok(ws.is_kernel_tls())
+    });
+
+    let connector = websocket::TlsTcpConnector::new(
+        websocket::ClientTlsOptions {
+            server_name: "localhost".to_string(),
+            enable_sni: true,
+            trust: tls::TrustStore {
+                web_roots: false,
+                system_roots: false,
+                ca_paths: vec![ca_cert_path.to_string()],
+            },
+            pins: vec![],
+            policy: policy.clone(),
+            session_cache: None,
+        },
+        dial::Dialer::default(),
+        websocket::UpgradeRequest {
+            host: "localhost".to_string(),
+            path: "/".to_string(),
+            headers: Default::default(),
+            auth: auth(),
+        },
+    )?;
+    let mut ws = connector.connect(&addr.to_string())?;
+
+    let message = [0x45u8; MESSAGE_SIZE];
+    let start = Instant::now();
+    for _ in 0..messages {
+        ws.send(&message)?;
+    }
+    ws.flush()?;
+    let mut buf = [0u8; 16];
+    ws.recv(&mut buf)?;
+    let seconds = start.elapsed().as_secs_f64();
+
+    let server_kernel = server
+        .join()
+        .map_err(|_| anyhow!("server thread panicked"))??;
+    Ok((seconds, server_kernel && ws.is_kernel_tls()))
+}
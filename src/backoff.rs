@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+/// Exponential backoff between reconnect attempts.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    /// The first delay is `initial`, each next one doubles, up to `max`.
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            current: initial.min(max),
+        }
+    }
+
+    /// Returns the delay before the next attempt, jittered into the upper half
+    /// so clients restarted together spread out.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = self
+            .current
+            .checked_mul(2)
+            .unwrap_or(self.max)
+            .min(self.max);
+
+        let half = delay / 2;
+        half + half.mul_f64(rand::random::<f64>())
+    }
+
+    /// Starts over from the initial delay, e.g. after a stable session.
+    pub fn reset(&mut self) {
+        self.current = self.initial.min(self.max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_double_up_to_max() {
+        let max = Duration::from_secs(60);
+        let mut backoff = Backoff::new(Duration::from_secs(1), max);
+        for &expected in &[1, 2, 4, 8, 16, 32, 60, 60] {
+            let expected = Duration::from_secs(expected);
+            assert_eq!(backoff.current, expected);
+            let delay = backoff.next_delay();
+            assert!(delay >= expected / 2 && delay <= expected, "{:?}", delay);
+        }
+
+        for _ in 0..1000 {
+            let delay = backoff.next_delay();
+            assert!(delay >= max / 2 && delay <= max, "{:?}", delay);
+        }
+        assert_eq!(backoff.current, max);
+
+        backoff.reset();
+        assert_eq!(backoff.current, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn delays_do_not_overflow() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::MAX);
+        for _ in 0..200 {
+            backoff.next_delay();
+        }
+        assert_eq!(backoff.current, Duration::MAX);
+
+        let mut backoff = Backoff::new(Duration::from_secs(10), Duration::from_secs(1));
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+}
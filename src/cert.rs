@@ -0,0 +1,337 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+use anyhow::anyhow;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose, SanType, SignatureAlgorithm,
+};
+
+use crate::sockets::tls;
+
+/// Type of the generated keys. RSA is not offered, ring cannot generate it.
+#[derive(Clone, Copy)]
+pub enum KeyType {
+    EcdsaP256,
+    EcdsaP384,
+    Ed25519,
+}
+
+impl KeyType {
+    /// Parses `ecdsa-p256`, `ecdsa-p384` or `ed25519`.
+    pub fn parse(s: &str) -> io::Result<Self> {
+        match s {
+            "ecdsa-p256" => Ok(KeyType::EcdsaP256),
+            "ecdsa-p384" => Ok(KeyType::EcdsaP384),
+            "ed25519" => Ok(KeyType::Ed25519),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                anyhow!(
+                    "unknown key type {:?}, expect ecdsa-p256, ecdsa-p384 or ed25519",
+                    s
+                ),
+            )),
+        }
+    }
+
+    fn algorithm(self) -> &'static SignatureAlgorithm {
+        match self {
+            KeyType::EcdsaP256 => &rcgen::PKCS_ECDSA_P256_SHA256,
+            KeyType::EcdsaP384 => &rcgen::PKCS_ECDSA_P384_SHA384,
+            KeyType::Ed25519 => &rcgen::PKCS_ED25519,
+        }
+    }
+}
+
+/// A certificate and its unencrypted PKCS#8 private key, both PEM encoded.
+pub struct Issued {
+    pub cert_pem: String,
+    pub key_pem: String,
+}
+
+impl Issued {
+    /// Writes the certificate and the key, the key readable by the owner only.
+    /// Existing files are only replaced when `overwrite` is set.
+    pub fn write(&self, cert_path: &str, key_path: &str, overwrite: bool) -> io::Result<()> {
+        let mut key_file = create_file(key_path, 0o600, overwrite)?;
+        let mut cert_file = match create_file(cert_path, 0o644, overwrite) {
+            Ok(file) => file,
+            Err(e) => {
+                if !overwrite {
+                    let _ = fs::remove_file(key_path);
+                }
+                return Err(e);
+            }
+        };
+
+        key_file.write_all(self.key_pem.as_bytes())?;
+        cert_file.write_all(self.cert_pem.as_bytes())
+    }
+}
+
+/// Opens `path` for writing with permissions `mode`, failing if it exists unless `overwrite`.
+fn create_file(path: &str, mode: u32, overwrite: bool) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).mode(mode);
+    if overwrite {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let file = options.open(path).map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            anyhow!("{} already exists", path),
+        ),
+        _ => e,
+    })?;
+    // the mode only applies to new files, a replaced key must not stay readable to others
+    file.set_permissions(fs::Permissions::from_mode(mode))?;
+    Ok(file)
+}
+
+/// Creates a self-signed CA certificate.
+pub fn init_ca(name: &str, days: u32, key_type: KeyType) -> io::Result<Issued> {
+    let mut params = params(name, days, key_type);
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+
+    let cert = Certificate::from_params(params).map_err(rcgen_error)?;
+    Ok(Issued {
+        cert_pem: cert.serialize_pem().map_err(rcgen_error)?,
+        key_pem: cert.serialize_private_key_pem(),
+    })
+}
+
+/// A CA loaded from files, e.g. those written by `init_ca`, to issue certificates with.
+pub struct Authority {
+    cert: Certificate,
+}
+
+impl Authority {
+    pub fn load(cert_path: &str, key_path: &str, key_password: Option<&str>) -> io::Result<Self> {
+        let certs = tls::load_certs(cert_path)?;
+        let ca_cert = certs.first().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                anyhow!("file {} does not contain any cert", cert_path),
+            )
+        })?;
+        let keys = tls::load_private_keys(key_path, key_password)?;
+        let key_pair = KeyPair::from_der(&keys[0].0).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                anyhow!(
+                    "unsupported CA key in {}, expect PKCS#8 or SEC1: {}",
+                    key_path,
+                    e
+                ),
+            )
+        })?;
+
+        let params = CertificateParams::from_ca_cert_der(&ca_cert.0, key_pair).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                anyhow!("invalid CA certificate {}: {}", cert_path, e),
+            )
+        })?;
+        let cert = Certificate::from_params(params).map_err(rcgen_error)?;
+        Ok(Self { cert })
+    }
+
+    /// Issues a server certificate for the names and IP addresses in `sans`,
+    /// the first one is also the common name.
+    pub fn issue_server(
+        &self,
+        sans: &[String],
+        days: u32,
+        key_type: KeyType,
+    ) -> io::Result<Issued> {
+        let first = sans.first().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                anyhow!("a server certificate needs at least one subject alternative name"),
+            )
+        })?;
+
+        let mut params = params(first, days, key_type);
+        params.subject_alt_names = sans
+            .iter()
+            .map(|san| match san.parse::<IpAddr>() {
+                Ok(ip) => SanType::IpAddress(ip),
+                Err(_) => SanType::DnsName(san.clone()),
+            })
+            .collect();
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        self.issue(params)
+    }
+
+    /// Issues a client certificate with `name` as the common name.
+    pub fn issue_client(&self, name: &str, days: u32, key_type: KeyType) -> io::Result<Issued> {
+        let mut params = params(name, days, key_type);
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        self.issue(params)
+    }
+
+    fn issue(&self, params: CertificateParams) -> io::Result<Issued> {
+        let cert = Certificate::from_params(params).map_err(rcgen_error)?;
+        Ok(Issued {
+            cert_pem: cert
+                .serialize_pem_with_signer(&self.cert)
+                .map_err(rcgen_error)?,
+            key_pem: cert.serialize_private_key_pem(),
+        })
+    }
+}
+
+/// Parameters valid from a day ago, to tolerate clock skew, for `days` days.
+fn params(common_name: &str, days: u32, key_type: KeyType) -> CertificateParams {
+    let now = time::OffsetDateTime::now_utc();
+
+    let mut params = CertificateParams::default();
+    params.alg = key_type.algorithm();
+    params.not_before = now - time::Duration::days(1);
+    params.not_after = now + time::Duration::days(days.into());
+    params.serial_number = Some(rand::random::<u64>() >> 1);
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, common_name);
+    params
+}
+
+fn rcgen_error(e: rcgen::RcgenError) -> io::Error {
+    io::Error::other(anyhow!("could not generate certificate: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::ServerCertVerifier;
+    use std::env;
+    use std::path::PathBuf;
+    use x509_parser::extensions::GeneralName;
+
+    /// A directory of its own for each test, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("tunnel-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.0.join(name).to_str().unwrap().to_string()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn ip_sans(cert: &rustls::Certificate) -> Vec<IpAddr> {
+        let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).unwrap();
+        let san = cert.tbs_certificate.subject_alternative_name().unwrap();
+        san.unwrap()
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::IPAddress(&[a, b, c, d]) => Some(IpAddr::from([a, b, c, d])),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn issued_certificates_verify() {
+        let dir = TempDir::new("issued");
+        for &key_type in &[KeyType::EcdsaP256, KeyType::EcdsaP384, KeyType::Ed25519] {
+            let (ca_cert, ca_key) = (dir.path("ca.crt"), dir.path("ca.key"));
+            init_ca("Tunnel CA", 30, key_type)
+                .unwrap()
+                .write(&ca_cert, &ca_key, true)
+                .unwrap();
+            let ca = Authority::load(&ca_cert, &ca_key, None).unwrap();
+
+            let (server_cert, server_key) = (dir.path("server.crt"), dir.path("server.key"));
+            let sans = ["tunnel.example.com".to_string(), "192.0.2.1".to_string()];
+            ca.issue_server(&sans, 30, key_type)
+                .unwrap()
+                .write(&server_cert, &server_key, true)
+                .unwrap();
+            let (client_cert, client_key) = (dir.path("client.crt"), dir.path("client.key"));
+            ca.issue_client("steven", 30, key_type)
+                .unwrap()
+                .write(&client_cert, &client_key, true)
+                .unwrap();
+
+            // as the client does with --ca-cert-path
+            let mut roots = rustls::RootCertStore::empty();
+            tls::TrustStore {
+                web_roots: false,
+                system_roots: false,
+                ca_paths: vec![ca_cert.clone()],
+            }
+            .load(&mut roots)
+            .unwrap();
+
+            let certs = tls::load_certs(&server_cert).unwrap();
+            let keys = tls::load_private_keys(&server_key, None).unwrap();
+            rustls::sign::any_supported_type(&keys[0]).unwrap();
+            let name = webpki::DNSNameRef::try_from_ascii_str("tunnel.example.com").unwrap();
+            rustls::WebPKIVerifier::new()
+                .verify_server_cert(&roots, &certs, name, &[])
+                .unwrap();
+            let other = webpki::DNSNameRef::try_from_ascii_str("other.example.com").unwrap();
+            assert!(rustls::WebPKIVerifier::new()
+                .verify_server_cert(&roots, &certs, other, &[])
+                .is_err());
+            // webpki 0.21 cannot verify IP names, so look at the SAN itself
+            assert_eq!(
+                ip_sans(&certs[0]),
+                vec!["192.0.2.1".parse::<IpAddr>().unwrap()]
+            );
+
+            let certs = tls::load_certs(&client_cert).unwrap();
+            let keys = tls::load_private_keys(&client_key, None).unwrap();
+            rustls::sign::any_supported_type(&keys[0]).unwrap();
+            rustls::AllowAnyAuthenticatedClient::new(roots)
+                .verify_client_cert(&certs, None)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn write_keeps_existing_files_unless_overwrite() {
+        let dir = TempDir::new("write");
+        let (cert, key) = (dir.path("ca.crt"), dir.path("ca.key"));
+        fs::write(&key, "old key").unwrap();
+        fs::set_permissions(&key, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let issued = init_ca("Tunnel CA", 30, KeyType::EcdsaP256).unwrap();
+        let e = issued.write(&cert, &key, false).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&key).unwrap(), "old key");
+        assert!(fs::metadata(&cert).is_err());
+
+        issued.write(&cert, &key, true).unwrap();
+        assert_eq!(fs::read_to_string(&key).unwrap(), issued.key_pem);
+        let mode = fs::metadata(&key).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
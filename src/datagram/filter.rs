@@ -0,0 +1,43 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use super::traits::{Rx, Tx};
+
+/// Drops the datagrams received from `inner` which `accept` rejects.
+pub struct Filter<T, F> {
+    inner: T,
+    accept: F,
+}
+
+impl<T, F> Filter<T, F> {
+    pub fn new(inner: T, accept: F) -> Self {
+        Self { inner, accept }
+    }
+}
+
+impl<T: Rx, F: Fn(&[u8]) -> bool> Rx for Filter<T, F> {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.inner.recv(buf)?;
+            if (self.accept)(&buf[..n]) {
+                return Ok(n);
+            }
+        }
+    }
+}
+
+impl<T: Tx, F> Tx for Filter<T, F> {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: AsRawFd, F> AsRawFd for Filter<T, F> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
@@ -1,5 +1,7 @@
+mod filter;
 mod run_loop;
 mod traits;
 
+pub use filter::*;
 pub use run_loop::*;
 pub use traits::*;
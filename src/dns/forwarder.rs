@@ -0,0 +1,489 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::anyhow;
+
+use super::message::{self, Question};
+use crate::sockets::dial::raw_sockaddr;
+
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(3);
+/// Idle TCP clients are dropped after this.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+const HOSTS_TTL: u32 = 60;
+/// Queries and TCP connections handled at once, further ones are dropped.
+const MAX_IN_FLIGHT: usize = 64;
+
+/// Names answered without asking the upstreams, loaded from a file in the `/etc/hosts` format.
+#[derive(Debug, Default)]
+pub struct Hosts(HashMap<String, Vec<IpAddr>>);
+
+impl Hosts {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let mut hosts: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let addr = match fields.next() {
+                Some(addr) => addr,
+                None => continue,
+            };
+            let addr: IpAddr = addr.parse().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    anyhow!("{:?}:{}: invalid address {:?}: {}", path, i + 1, addr, e),
+                )
+            })?;
+            for name in fields {
+                let name = name.trim_end_matches('.').to_ascii_lowercase();
+                hosts.entry(name).or_default().push(addr);
+            }
+        }
+        Ok(Self(hosts))
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&[IpAddr]> {
+        self.0.get(name).map(|addrs| addrs.as_slice())
+    }
+}
+
+/// Called with the question and every response of an upstream, before it is passed on.
+pub type Responded = dyn Fn(&Question, &[u8]) + Send + Sync;
+
+/// A DNS forwarder, which answers the names in its hosts and asks the upstreams, in order,
+/// for the rest. Queries over TCP go over TCP to the upstreams as well.
+pub struct Forwarder {
+    upstreams: Vec<SocketAddr>,
+    hosts: Hosts,
+    in_flight: AtomicUsize,
+    /// E.g. to route the answered addresses through the tunnel.
+    pub on_response: Option<Box<Responded>>,
+}
+
+impl Forwarder {
+    pub fn new(upstreams: Vec<SocketAddr>, hosts: Hosts) -> Self {
+        Self {
+            upstreams,
+            hosts,
+            in_flight: AtomicUsize::new(0),
+            on_response: None,
+        }
+    }
+
+    /// Listens on `addr` with UDP and TCP in the background. The address does not need to
+    /// exist yet, e.g. when it is assigned to the tun device later.
+    pub fn serve(self: &Arc<Self>, addr: SocketAddr) -> io::Result<()> {
+        let udp_socket: UdpSocket = bind(&addr, libc::SOCK_DGRAM)
+            .map_err(|e| io::Error::new(e.kind(), anyhow!("could not bind udp {}: {}", addr, e)))?;
+        let tcp_listener: TcpListener = bind(&addr, libc::SOCK_STREAM)
+            .map_err(|e| io::Error::new(e.kind(), anyhow!("could not bind tcp {}: {}", addr, e)))?;
+
+        let forwarder = self.clone();
+        thread::spawn(move || forwarder.serve_udp(udp_socket));
+        let forwarder = self.clone();
+        thread::spawn(move || forwarder.serve_tcp(tcp_listener));
+        log::info!("dns forwarder listens on {}", addr);
+        Ok(())
+    }
+
+    /// The response to `query`, SERVFAIL when no upstream answers.
+    /// None if the query cannot be parsed, it is dropped without asking the upstreams.
+    pub fn resolve(&self, query: &[u8], tcp: bool) -> Option<Vec<u8>> {
+        let question = match Question::parse(query) {
+            Some(question) => question,
+            None => {
+                log::debug!("drop dns query which cannot be parsed");
+                return None;
+            }
+        };
+        if question.is_address() {
+            if let Some(addrs) = self.hosts.lookup(&question.name) {
+                log::debug!("answer {} from the hosts", question.name);
+                return Some(message::answer(query, &question, addrs, HOSTS_TTL));
+            }
+        }
+
+        for upstream in &self.upstreams {
+            let result = if tcp {
+                exchange_tcp(upstream, query)
+            } else {
+                exchange_udp(upstream, query)
+            };
+            match result {
+                Ok(response) => {
+                    if let Some(ref on_response) = self.on_response {
+                        on_response(&question, &response);
+                    }
+                    return Some(response);
+                }
+                Err(e) => log::debug!("dns upstream {} failed: {}", upstream, e),
+            }
+        }
+        log::warn!("no dns upstream answered for {}", question.name);
+        Some(message::failure(query, &question))
+    }
+
+    fn serve_udp(self: Arc<Self>, udp_socket: UdpSocket) {
+        let mut buf = vec![0; 65535];
+        loop {
+            let (n, peer) = match udp_socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    log::error!("dns forwarder stopped receiving: {}", e);
+                    return;
+                }
+            };
+            let slot = match self.acquire() {
+                Some(slot) => slot,
+                None => {
+                    log::debug!("drop dns query from {}, too many in flight", peer);
+                    continue;
+                }
+            };
+            let query = buf[..n].to_vec();
+            let reply_socket = match udp_socket.try_clone() {
+                Ok(socket) => socket,
+                Err(e) => {
+                    log::error!("could not clone the dns socket: {}", e);
+                    continue;
+                }
+            };
+            thread::spawn(move || {
+                if let Some(response) = slot.0.resolve(&query, false) {
+                    if let Err(e) = reply_socket.send_to(&response, peer) {
+                        log::debug!("could not answer dns query from {}: {}", peer, e);
+                    }
+                }
+            });
+        }
+    }
+
+    fn serve_tcp(self: Arc<Self>, tcp_listener: TcpListener) {
+        loop {
+            let (stream, peer) = match tcp_listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    log::warn!("could not accept dns connection: {}", e);
+                    continue;
+                }
+            };
+            let slot = match self.acquire() {
+                Some(slot) => slot,
+                None => {
+                    log::debug!("drop dns connection from {}, too many in flight", peer);
+                    continue;
+                }
+            };
+            thread::spawn(move || {
+                if let Err(e) = slot.0.handle_tcp(stream) {
+                    log::debug!("dns connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    /// Answers the queries of a TCP client until it closes the connection.
+    fn handle_tcp(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        loop {
+            let query = match read_tcp_message(&mut stream) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                result => result?,
+            };
+            match self.resolve(&query, true) {
+                Some(response) => write_tcp_message(&mut stream, &response)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// One of the `MAX_IN_FLIGHT` slots, None if all are taken.
+    fn acquire(self: &Arc<Self>) -> Option<Slot> {
+        // counted before the check, so the slot releases it either way
+        let slot = Slot(self.clone());
+        if self.in_flight.fetch_add(1, Ordering::SeqCst) < MAX_IN_FLIGHT {
+            Some(slot)
+        } else {
+            None
+        }
+    }
+}
+
+/// A query or TCP connection in flight, released when dropped, even by a panic.
+struct Slot(Arc<Forwarder>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn exchange_udp(upstream: &SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let local: IpAddr = match upstream {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let udp_socket = UdpSocket::bind((local, 0))?;
+    udp_socket.connect(upstream)?;
+    udp_socket.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+    udp_socket.send(query)?;
+
+    let mut buf = vec![0; 65535];
+    loop {
+        let n = udp_socket.recv(&mut buf)?;
+        // skips stray responses to earlier queries
+        if n >= 2 && buf[..2] == query[..2] {
+            buf.truncate(n);
+            return Ok(buf);
+        }
+    }
+}
+
+fn exchange_tcp(upstream: &SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(upstream, UPSTREAM_TIMEOUT)?;
+    stream.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+    stream.set_write_timeout(Some(UPSTREAM_TIMEOUT))?;
+    write_tcp_message(&mut stream, query)?;
+    read_tcp_message(&mut stream)
+}
+
+/// Reads a message prefixed by its 2 byte length, as DNS does over TCP.
+fn read_tcp_message(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut msg = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut msg)?;
+    Ok(msg)
+}
+
+fn write_tcp_message(stream: &mut TcpStream, msg: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(msg.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, anyhow!("message too long")))?;
+    let mut buf = Vec::with_capacity(2 + msg.len());
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(msg);
+    stream.write_all(&buf)
+}
+
+/// Binds a socket of `kind` to `addr` with `IP_FREEBIND`, so the address may be added later.
+fn bind<S: FromRawFd>(addr: &SocketAddr, kind: libc::c_int) -> io::Result<S> {
+    let (family, level, freebind) = match addr {
+        SocketAddr::V4(_) => (libc::AF_INET, libc::IPPROTO_IP, libc::IP_FREEBIND),
+        SocketAddr::V6(_) => (libc::AF_INET6, libc::IPPROTO_IPV6, libc::IPV6_FREEBIND),
+    };
+    let fd = unsafe { libc::socket(family, kind | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // owns the socket from here on, to close it on errors
+    let socket = unsafe { S::from_raw_fd(fd) };
+
+    setsockopt(fd, level, freebind)?;
+    if kind == libc::SOCK_STREAM {
+        setsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR)?;
+    }
+    let (sockaddr, len) = raw_sockaddr(addr);
+    if unsafe { libc::bind(fd, &sockaddr as *const _ as *const libc::sockaddr, len) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if kind == libc::SOCK_STREAM && unsafe { libc::listen(fd, 128) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+/// Turns the boolean socket option `name` on.
+fn setsockopt(fd: libc::c_int, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &on as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// A query for `name` of `qtype` with `id`.
+    fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut msg = id.to_be_bytes().to_vec();
+        msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            msg.push(label.len() as u8);
+            msg.extend_from_slice(label.as_bytes());
+        }
+        msg.push(0);
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&message::CLASS_IN.to_be_bytes());
+        msg
+    }
+
+    /// The response of a stub upstream, which answers every name with 192.0.2.53.
+    fn upstream_answer(query: &[u8]) -> Vec<u8> {
+        let question = Question::parse(query).unwrap();
+        message::answer(query, &question, &["192.0.2.53".parse().unwrap()], 30)
+    }
+
+    /// A stub upstream on UDP and TCP of the same port.
+    fn stub_upstream() -> SocketAddr {
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp_listener.local_addr().unwrap();
+        let udp_socket = UdpSocket::bind(addr).unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            loop {
+                let (n, peer) = udp_socket.recv_from(&mut buf).unwrap();
+                // a stray response first, which the forwarder skips
+                let mut stray = upstream_answer(&buf[..n]);
+                stray[0] ^= 0xff;
+                udp_socket.send_to(&stray, peer).unwrap();
+                udp_socket
+                    .send_to(&upstream_answer(&buf[..n]), peer)
+                    .unwrap();
+            }
+        });
+        thread::spawn(move || {
+            for stream in tcp_listener.incoming() {
+                let mut stream = stream.unwrap();
+                let query = read_tcp_message(&mut stream).unwrap();
+                write_tcp_message(&mut stream, &upstream_answer(&query)).unwrap();
+            }
+        });
+        addr
+    }
+
+    /// An address nothing listens on, over UDP or TCP.
+    fn closed_port() -> SocketAddr {
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        tcp_listener.local_addr().unwrap()
+    }
+
+    #[test]
+    fn resolve_over_udp_and_tcp() {
+        let upstream = stub_upstream();
+        let forwarder = Forwarder::new(vec![closed_port(), upstream], Hosts::default());
+        for &tcp in &[false, true] {
+            let query = query(0x4242, "www.example.com", message::TYPE_A);
+            let response = forwarder.resolve(&query, tcp).unwrap();
+            assert_eq!(response, upstream_answer(&query), "tcp: {}", tcp);
+        }
+    }
+
+    #[test]
+    fn hosts_answer_without_upstreams() {
+        let mut hosts = HashMap::new();
+        hosts.insert(
+            "printer.lan".to_string(),
+            vec!["192.168.1.9".parse().unwrap(), "fd00::9".parse().unwrap()],
+        );
+        let forwarder = Forwarder::new(vec![closed_port()], Hosts(hosts));
+
+        let response = forwarder
+            .resolve(&query(1, "Printer.LAN", message::TYPE_AAAA), false)
+            .unwrap();
+        assert_eq!(
+            message::addresses(&response),
+            vec![("fd00::9".parse().unwrap(), HOSTS_TTL)]
+        );
+    }
+
+    #[test]
+    fn servfail_without_upstreams() {
+        let forwarder = Forwarder::new(vec![closed_port()], Hosts::default());
+        for &tcp in &[false, true] {
+            let response = forwarder
+                .resolve(&query(7, "www.example.com", message::TYPE_A), tcp)
+                .unwrap();
+            assert_eq!(response[3] & 0x0f, 2, "tcp: {}", tcp);
+        }
+        assert!(forwarder.resolve(&[0; 5], false).is_none());
+    }
+
+    #[test]
+    fn unparseable_queries_are_dropped() {
+        let upstream = stub_upstream();
+        let forwarder = Arc::new(Forwarder::new(vec![upstream], Hosts::default()));
+        for query in &[&[][..], &[0x42][..], &[0; 12][..]] {
+            assert!(forwarder.resolve(query, false).is_none(), "{:?}", query);
+        }
+        for _ in 0..MAX_IN_FLIGHT + 1 {
+            drop(forwarder.acquire().unwrap());
+        }
+        assert_eq!(forwarder.in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn responses_are_reported() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut forwarder = Forwarder::new(vec![stub_upstream()], Hosts::default());
+        let reported = seen.clone();
+        forwarder.on_response = Some(Box::new(move |question, response| {
+            let addrs = message::addresses(response);
+            reported
+                .lock()
+                .unwrap()
+                .push((question.name.clone(), addrs));
+        }));
+
+        forwarder
+            .resolve(&query(9, "www.example.com", message::TYPE_A), false)
+            .unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![(
+                "www.example.com".to_string(),
+                vec![("192.0.2.53".parse().unwrap(), 30)]
+            )]
+        );
+    }
+
+    #[test]
+    fn serve_over_udp_and_tcp() {
+        let forwarder = Arc::new(Forwarder::new(vec![stub_upstream()], Hosts::default()));
+        let addr = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        forwarder.serve(addr).unwrap();
+
+        let query = query(3, "www.example.com", message::TYPE_A);
+        let udp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp_socket.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+        udp_socket.send_to(&query, addr).unwrap();
+        let mut buf = [0; 512];
+        let n = udp_socket.recv(&mut buf).unwrap();
+        assert_eq!(buf[..n], upstream_answer(&query)[..]);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        for _ in 0..2 {
+            write_tcp_message(&mut stream, &query).unwrap();
+            assert_eq!(
+                read_tcp_message(&mut stream).unwrap(),
+                upstream_answer(&query)
+            );
+        }
+    }
+}
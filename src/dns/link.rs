@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+
+/// DNS servers and search domains the server pushes to its clients.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DnsConfig {
+    pub servers: Vec<IpAddr>,
+    pub search: Vec<String>,
+}
+
+impl DnsConfig {
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty() && self.search.is_empty()
+    }
+
+    /// Loads per user settings, one `USERNAME[,dns=ADDR][,search=DOMAIN]...` per line,
+    /// `dns` and `search` can be repeated. Empty lines and lines starting with `#` are ignored.
+    pub fn load_users(path: &Path) -> io::Result<HashMap<String, Self>> {
+        let content = fs::read_to_string(path)?;
+        let mut users = HashMap::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    anyhow!("{:?}:{}: {}", path, i + 1, reason),
+                )
+            };
+
+            let mut parts = line.split(',').map(|p| p.trim());
+            let username = parts.next().unwrap_or_default();
+            let mut config = Self::default();
+            for part in parts {
+                match part.split_once('=') {
+                    Some(("dns", addr)) => config.servers.push(
+                        addr.parse()
+                            .map_err(|e| invalid(format!("invalid dns {:?}: {}", addr, e)))?,
+                    ),
+                    Some(("search", domain)) if is_search_domain(domain) => {
+                        config.search.push(domain.to_string())
+                    }
+                    Some(("search", domain)) => {
+                        return Err(invalid(format!("invalid search domain {:?}", domain)))
+                    }
+                    _ => {
+                        return Err(invalid(format!(
+                            "unknown option {:?}, expect dns=ADDR or search=DOMAIN",
+                            part
+                        )))
+                    }
+                }
+            }
+            users.insert(username.to_string(), config);
+        }
+        Ok(users)
+    }
+}
+
+/// Whether `domain` is a host name, which is safe to pass on to `resolvectl` and `resolvconf`,
+/// unlike e.g. `~.` or names starting with `-`. A trailing dot is allowed.
+pub fn is_search_domain(domain: &str) -> bool {
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    !domain.is_empty()
+        && domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+/// Applies pushed DNS settings to the tun device, with systemd-resolved when it runs,
+/// otherwise with `resolvconf`. The prior settings come back with `restore`.
+pub struct LinkDns {
+    tun_name: String,
+    applied: Mutex<Option<DnsConfig>>,
+}
+
+impl LinkDns {
+    pub fn new(tun_name: &str) -> Self {
+        Self {
+            tun_name: tun_name.to_string(),
+            applied: Mutex::new(None),
+        }
+    }
+
+    /// Applies `config` unless it is applied already, an empty one restores the prior settings.
+    pub fn update(&self, config: &DnsConfig) {
+        let mut applied = self.applied.lock().unwrap();
+        if applied.as_ref() == Some(config) || (applied.is_none() && config.is_empty()) {
+            return;
+        }
+        if config.is_empty() {
+            drop(applied);
+            self.restore();
+            return;
+        }
+
+        match self.apply(config) {
+            Ok(()) => {
+                log::info!(
+                    "use dns {:?} search {:?} on {}",
+                    config.servers,
+                    config.search,
+                    self.tun_name
+                );
+                *applied = Some(config.clone());
+            }
+            Err(e) => log::warn!("could not apply the pushed dns settings: {}", e),
+        }
+    }
+
+    fn apply(&self, config: &DnsConfig) -> io::Result<()> {
+        let servers: Vec<String> = config.servers.iter().map(|s| s.to_string()).collect();
+        if uses_resolved() {
+            let mut dns = vec!["dns", &self.tun_name];
+            dns.extend(servers.iter().map(|s| s.as_str()));
+            run("resolvectl", &dns, None)?;
+            let mut domain = vec!["domain", &self.tun_name];
+            domain.extend(config.search.iter().map(|s| s.as_str()));
+            run("resolvectl", &domain, None)
+        } else {
+            let mut content = String::new();
+            for server in &servers {
+                content.push_str(&format!("nameserver {}\n", server));
+            }
+            if !config.search.is_empty() {
+                content.push_str(&format!("search {}\n", config.search.join(" ")));
+            }
+            // exclusive, the other interfaces' nameservers are not used meanwhile
+            let interface = format!("{}.inet", self.tun_name);
+            run("resolvconf", &["-x", "-a", &interface], Some(&content))
+        }
+    }
+
+    /// Restores the settings from before the first update, later calls do nothing.
+    pub fn restore(&self) {
+        let mut applied = self.applied.lock().unwrap();
+        if applied.take().is_none() {
+            return;
+        }
+        match revert(&self.tun_name) {
+            Ok(()) => log::info!("restored the dns settings"),
+            Err(e) => log::warn!("could not restore the dns settings: {}", e),
+        }
+    }
+}
+
+/// Drops the settings of `tun_name`, e.g. left by a crashed client.
+pub fn revert(tun_name: &str) -> io::Result<()> {
+    if uses_resolved() {
+        run("resolvectl", &["revert", tun_name], None)
+    } else {
+        let interface = format!("{}.inet", tun_name);
+        run("resolvconf", &["-f", "-d", &interface], None)
+    }
+}
+
+fn uses_resolved() -> bool {
+    Path::new("/run/systemd/resolve").is_dir()
+}
+
+fn run(program: &str, args: &[&str], input: Option<&str>) -> io::Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        // spared by signals to the client's process group, e.g. Ctrl-C, while cleaning up
+        .process_group(0)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), anyhow!("could not run {}: {}", program, e)))?;
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(anyhow!(
+            "{} {} failed with {}: {}",
+            program,
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_domains() {
+        for domain in &[
+            "example.com",
+            "example.com.",
+            "corp",
+            "a-b.x1.example",
+            "xn--bcher-kva.example",
+        ] {
+            assert!(is_search_domain(domain), "{}", domain);
+        }
+        for domain in &[
+            "",
+            ".",
+            "~.",
+            "~example.com",
+            "-example.com",
+            "example-.com",
+            "example..com",
+            ".example.com",
+            "exa mple.com",
+            "example.com;rm",
+            "under_score.example",
+        ] {
+            assert!(!is_search_domain(domain), "{}", domain);
+        }
+        assert!(is_search_domain(&"a".repeat(63)));
+        assert!(!is_search_domain(&"a".repeat(64)));
+        assert!(!is_search_domain(&["a"; 128].join(".")));
+    }
+}
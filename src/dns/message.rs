@@ -0,0 +1,345 @@
+use std::convert::TryInto;
+use std::net::IpAddr;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+pub const CLASS_IN: u16 = 1;
+
+const HEADER_LEN: usize = 12;
+const RCODE_SERVFAIL: u8 = 2;
+
+/// The first question of a DNS message, the name is lowercase without the trailing dot.
+#[derive(Debug)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+    end: usize,
+}
+
+impl Question {
+    pub fn parse(msg: &[u8]) -> Option<Self> {
+        if msg.len() < HEADER_LEN || read_u16(msg, 4)? == 0 {
+            return None;
+        }
+        let (name, offset) = read_name(msg, HEADER_LEN)?;
+        Some(Self {
+            name,
+            qtype: read_u16(msg, offset)?,
+            qclass: read_u16(msg, offset + 2)?,
+            end: offset + 4,
+        })
+    }
+
+    /// Whether it asks for the addresses of the name.
+    pub fn is_address(&self) -> bool {
+        self.qclass == CLASS_IN && (self.qtype == TYPE_A || self.qtype == TYPE_AAAA)
+    }
+}
+
+/// The response to `query` answering `question` with the `addrs` of its type.
+pub fn answer(query: &[u8], question: &Question, addrs: &[IpAddr], ttl: u32) -> Vec<u8> {
+    let addrs: Vec<_> = addrs
+        .iter()
+        .filter(|addr| addr.is_ipv6() == (question.qtype == TYPE_AAAA))
+        .collect();
+    let mut msg = response(query, question, 0);
+    msg[6..8].copy_from_slice(&(addrs.len() as u16).to_be_bytes());
+    for addr in addrs {
+        // a pointer to the name in the question
+        msg.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        msg.extend_from_slice(&question.qtype.to_be_bytes());
+        msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+        msg.extend_from_slice(&ttl.to_be_bytes());
+        match addr {
+            IpAddr::V4(addr) => {
+                msg.extend_from_slice(&4u16.to_be_bytes());
+                msg.extend_from_slice(&addr.octets());
+            }
+            IpAddr::V6(addr) => {
+                msg.extend_from_slice(&16u16.to_be_bytes());
+                msg.extend_from_slice(&addr.octets());
+            }
+        }
+    }
+    msg
+}
+
+/// The addresses in the answers of `msg` with their TTLs, also those of CNAME targets.
+pub fn addresses(msg: &[u8]) -> Vec<(IpAddr, u32)> {
+    read_addresses(msg).unwrap_or_default()
+}
+
+/// Whether `name` is `domain` or under it, both lowercase without the trailing dot.
+pub fn is_subdomain(name: &str, domain: &str) -> bool {
+    name == domain
+        || (name.len() > domain.len()
+            && name.ends_with(domain)
+            && name.as_bytes()[name.len() - domain.len() - 1] == b'.')
+}
+
+/// The SERVFAIL response to `query`.
+pub fn failure(query: &[u8], question: &Question) -> Vec<u8> {
+    response(query, question, RCODE_SERVFAIL)
+}
+
+/// The header and question of `query`, made a response without records.
+fn response(query: &[u8], question: &Question, rcode: u8) -> Vec<u8> {
+    let mut msg = query[..question.end].to_vec();
+    // QR, keeps the opcode and RD
+    msg[2] = 0x80 | (query[2] & 0x79);
+    // RA
+    msg[3] = 0x80 | rcode;
+    msg[4..HEADER_LEN].copy_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    msg
+}
+
+fn read_addresses(msg: &[u8]) -> Option<Vec<(IpAddr, u32)>> {
+    let questions = read_u16(msg, 4)?;
+    let answers = read_u16(msg, 6)?;
+    let mut offset = HEADER_LEN;
+    for _ in 0..questions {
+        offset = read_name(msg, offset)?.1 + 4;
+    }
+
+    let mut addrs = Vec::new();
+    for _ in 0..answers {
+        offset = read_name(msg, offset)?.1;
+        let rtype = read_u16(msg, offset)?;
+        let class = read_u16(msg, offset + 2)?;
+        let ttl = u32::from_be_bytes(msg.get(offset + 4..offset + 8)?.try_into().ok()?);
+        let len = read_u16(msg, offset + 8)? as usize;
+        let data = msg.get(offset + 10..offset + 10 + len)?;
+        match (rtype, class, len) {
+            (TYPE_A, CLASS_IN, 4) => {
+                let octets: [u8; 4] = data.try_into().ok()?;
+                addrs.push((octets.into(), ttl));
+            }
+            (TYPE_AAAA, CLASS_IN, 16) => {
+                let octets: [u8; 16] = data.try_into().ok()?;
+                addrs.push((octets.into(), ttl));
+            }
+            _ => (),
+        }
+        offset += 10 + len;
+    }
+    Some(addrs)
+}
+
+/// Reads the name at `offset`, following compression pointers.
+/// Returns it with the offset after it.
+fn read_name(msg: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *msg.get(offset)? as usize;
+        if len == 0 {
+            end.get_or_insert(offset + 1);
+            break;
+        }
+        match len & 0xc0 {
+            0xc0 => {
+                end.get_or_insert(offset + 2);
+                // pointers can loop
+                jumps += 1;
+                if jumps > 16 {
+                    return None;
+                }
+                offset = (read_u16(msg, offset)? & 0x3fff) as usize;
+            }
+            0 => {
+                let label = msg.get(offset + 1..offset + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                offset += 1 + len;
+            }
+            _ => return None,
+        }
+    }
+    Some((labels.join("."), end?))
+}
+
+fn read_u16(msg: &[u8], offset: usize) -> Option<u16> {
+    let bytes = msg.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A query for `name` with ID 0x1234 and RD set.
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut msg = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            msg.push(label.len() as u8);
+            msg.extend_from_slice(label.as_bytes());
+        }
+        msg.push(0);
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+        msg
+    }
+
+    /// Appends an answer record with the name at `pointer`.
+    fn record(msg: &mut Vec<u8>, pointer: u16, rtype: u16, ttl: u32, data: &[u8]) {
+        msg.extend_from_slice(&(0xc000 | pointer).to_be_bytes());
+        msg.extend_from_slice(&rtype.to_be_bytes());
+        msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+        msg.extend_from_slice(&ttl.to_be_bytes());
+        msg.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        msg.extend_from_slice(data);
+        let answers = read_u16(msg, 6).unwrap() + 1;
+        msg[6..8].copy_from_slice(&answers.to_be_bytes());
+    }
+
+    #[test]
+    fn parse_question() {
+        let msg = query("WWW.Example.com", TYPE_AAAA);
+        let question = Question::parse(&msg).unwrap();
+        assert_eq!(question.name, "www.example.com");
+        assert_eq!(question.qtype, TYPE_AAAA);
+        assert_eq!(question.qclass, CLASS_IN);
+        assert_eq!(question.end, msg.len());
+        assert!(question.is_address());
+
+        let question = Question::parse(&query("example.com", 16)).unwrap();
+        assert!(!question.is_address());
+
+        // the root
+        let mut msg = query("x", TYPE_A);
+        msg.drain(HEADER_LEN..HEADER_LEN + 2);
+        assert_eq!(Question::parse(&msg).unwrap().name, "");
+    }
+
+    #[test]
+    fn parse_truncated_or_without_question() {
+        let msg = query("www.example.com", TYPE_A);
+        for len in 0..msg.len() {
+            assert!(Question::parse(&msg[..len]).is_none(), "{}", len);
+        }
+
+        let mut msg = query("www.example.com", TYPE_A);
+        msg[4..6].copy_from_slice(&[0, 0]);
+        assert!(Question::parse(&msg).is_none());
+
+        // a label longer than the message, and the reserved label types
+        let mut msg = query("www", TYPE_A);
+        msg[HEADER_LEN] = 60;
+        assert!(Question::parse(&msg).is_none());
+        msg[HEADER_LEN] = 0x40;
+        assert!(Question::parse(&msg).is_none());
+    }
+
+    #[test]
+    fn read_compressed_names() {
+        let mut msg = query("example.com", TYPE_A);
+        let end = msg.len();
+        // www + a pointer to example.com in the question
+        msg.extend_from_slice(&[3, b'w', b'w', b'w', 0xc0, HEADER_LEN as u8]);
+        assert_eq!(
+            read_name(&msg, end).unwrap(),
+            ("www.example.com".to_string(), end + 6)
+        );
+
+        // a pointer to itself
+        let mut msg = query("example.com", TYPE_A);
+        let end = msg.len();
+        msg.extend_from_slice(&(0xc000 | end as u16).to_be_bytes());
+        assert!(read_name(&msg, end).is_none());
+
+        // two pointers to each other
+        let mut msg = query("example.com", TYPE_A);
+        let end = msg.len();
+        msg.extend_from_slice(&(0xc000 | (end + 2) as u16).to_be_bytes());
+        msg.extend_from_slice(&(0xc000 | end as u16).to_be_bytes());
+        assert!(read_name(&msg, end).is_none());
+
+        // a pointer past the end, and a pointer cut short
+        let mut msg = query("example.com", TYPE_A);
+        let end = msg.len();
+        msg.extend_from_slice(&[0xc0, 0xff]);
+        assert!(read_name(&msg, end).is_none());
+        msg.pop();
+        assert!(read_name(&msg, end).is_none());
+    }
+
+    #[test]
+    fn answer_from_addresses() {
+        let addrs: Vec<IpAddr> = vec![
+            "192.0.2.1".parse().unwrap(),
+            "2001:db8::1".parse().unwrap(),
+            "192.0.2.2".parse().unwrap(),
+        ];
+
+        let msg = query("host.example", TYPE_A);
+        let question = Question::parse(&msg).unwrap();
+        let response = answer(&msg, &question, &addrs, 60);
+        assert_eq!(response[..2], [0x12, 0x34]);
+        // QR, RD and RA, no error
+        assert_eq!(response[2..4], [0x81, 0x80]);
+        assert_eq!(read_u16(&response, 6), Some(2));
+        assert_eq!(addresses(&response), vec![(addrs[0], 60), (addrs[2], 60)]);
+        assert_eq!(Question::parse(&response).unwrap().name, "host.example");
+
+        let msg = query("host.example", TYPE_AAAA);
+        let question = Question::parse(&msg).unwrap();
+        let response = answer(&msg, &question, &addrs, 60);
+        assert_eq!(addresses(&response), vec![(addrs[1], 60)]);
+    }
+
+    #[test]
+    fn failure_is_servfail() {
+        let msg = query("host.example", TYPE_A);
+        let question = Question::parse(&msg).unwrap();
+        let response = failure(&msg, &question);
+        assert_eq!(response.len(), msg.len());
+        assert_eq!(response[2..4], [0x81, 0x80 | RCODE_SERVFAIL]);
+        assert_eq!(read_u16(&response, 6), Some(0));
+        assert!(addresses(&response).is_empty());
+    }
+
+    #[test]
+    fn addresses_of_answers() {
+        let mut msg = query("www.example.com", TYPE_A);
+        msg[2] |= 0x80;
+        let target = msg.len() + 12;
+        // a CNAME to cdn.example.net, then its addresses
+        let cname = [
+            3, b'c', b'd', b'n', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'n', b'e', b't',
+            0,
+        ];
+        record(&mut msg, HEADER_LEN as u16, 5, 300, &cname);
+        record(&mut msg, target as u16, TYPE_A, 20, &[192, 0, 2, 7]);
+        record(
+            &mut msg,
+            target as u16,
+            TYPE_AAAA,
+            30,
+            &[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 9],
+        );
+        // an A record of the wrong length is skipped
+        record(&mut msg, target as u16, TYPE_A, 40, &[192, 0, 2]);
+
+        assert_eq!(
+            addresses(&msg),
+            vec![
+                ("192.0.2.7".parse().unwrap(), 20),
+                ("2001:db8::9".parse().unwrap(), 30),
+            ]
+        );
+
+        // answers cut short give none at all
+        msg.truncate(msg.len() - 5);
+        assert!(addresses(&msg).is_empty());
+        assert!(addresses(&[0; 4]).is_empty());
+    }
+
+    #[test]
+    fn subdomains() {
+        assert!(is_subdomain("example.com", "example.com"));
+        assert!(is_subdomain("www.example.com", "example.com"));
+        assert!(!is_subdomain("badexample.com", "example.com"));
+        assert!(!is_subdomain("com", "example.com"));
+    }
+}
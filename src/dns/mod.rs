@@ -0,0 +1,7 @@
+mod forwarder;
+mod link;
+mod message;
+
+pub use forwarder::*;
+pub use link::*;
+pub use message::*;
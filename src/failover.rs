@@ -0,0 +1,187 @@
+use std::io;
+use std::os::unix::io::RawFd;
+use std::time::Instant;
+
+use rand::Rng;
+
+/// Health of the servers a client may connect to, to choose the next one.
+///
+/// The client sticks to the current server while it works. Otherwise it prefers the
+/// lowest priority among the servers which have not failed since they last worked,
+/// picking randomly by weight among equals. When all have failed, the one which
+/// failed longest ago is tried next. Servers which rejected the client are not tried again.
+pub struct ServerPool {
+    servers: Vec<Health>,
+    current: Option<usize>,
+}
+
+struct Health {
+    priority: u32,
+    weight: u32,
+    /// When the server last failed, cleared once it works again.
+    failed_at: Option<Instant>,
+    rejected: bool,
+}
+
+impl ServerPool {
+    /// Takes the priority and weight of each server, weights must not be 0.
+    pub fn new(servers: &[(u32, u32)]) -> Self {
+        Self {
+            servers: servers
+                .iter()
+                .map(|&(priority, weight)| Health {
+                    priority,
+                    weight,
+                    failed_at: None,
+                    rejected: false,
+                })
+                .collect(),
+            current: None,
+        }
+    }
+
+    /// Chooses the server to connect to.
+    pub fn select(&mut self) -> usize {
+        if let Some(i) = self.current {
+            if self.servers[i].failed_at.is_none() {
+                return i;
+            }
+        }
+
+        let best = self
+            .servers
+            .iter()
+            .filter(|s| s.failed_at.is_none() && !s.rejected)
+            .map(|s| s.priority)
+            .min();
+        let i = match best {
+            Some(best) => self.pick_weighted(best),
+            None => self
+                .servers
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| !s.rejected)
+                .min_by_key(|(_, s)| s.failed_at)
+                .map(|(i, _)| i)
+                .unwrap_or(0),
+        };
+        self.current = Some(i);
+        i
+    }
+
+    /// Picks randomly by weight among the healthy servers of `priority`.
+    fn pick_weighted(&self, priority: u32) -> usize {
+        let group: Vec<(usize, u64)> = self
+            .servers
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.failed_at.is_none() && !s.rejected && s.priority == priority)
+            .map(|(i, s)| (i, u64::from(s.weight)))
+            .collect();
+        let total = group
+            .iter()
+            .try_fold(0u64, |total, (_, w)| total.checked_add(*w));
+        let total = match total {
+            Some(total) if total > 0 => total,
+            _ => return group[0].0,
+        };
+
+        let mut pick = rand::thread_rng().gen_range(0..total);
+        for &(i, w) in &group {
+            if pick < w {
+                return i;
+            }
+            pick -= w;
+        }
+        group[0].0
+    }
+
+    /// Whether `i` has failed since it last worked, to back off before retrying it.
+    pub fn is_failing(&self, i: usize) -> bool {
+        self.servers[i].failed_at.is_some()
+    }
+
+    /// Marks `i` working, and the one to stick to.
+    pub fn connected(&mut self, i: usize) {
+        self.servers[i].failed_at = None;
+        self.current = Some(i);
+    }
+
+    pub fn failed(&mut self, i: usize) {
+        self.servers[i].failed_at = Some(Instant::now());
+    }
+
+    /// Marks `i` failed for good. Returns whether other servers remain to try.
+    pub fn rejected(&mut self, i: usize) -> bool {
+        self.failed(i);
+        self.servers[i].rejected = true;
+        self.servers.iter().any(|s| !s.rejected)
+    }
+
+    /// The servers preferred over `i`, most preferred first, to fail back to.
+    pub fn preferred_over(&self, i: usize) -> Vec<usize> {
+        let mut preferred: Vec<usize> = (0..self.servers.len())
+            .filter(|&j| {
+                !self.servers[j].rejected && self.servers[j].priority < self.servers[i].priority
+            })
+            .collect();
+        preferred.sort_by_key(|&j| self.servers[j].priority);
+        preferred
+    }
+}
+
+/// A duplicate of a session's socket, to end the session from another thread.
+pub struct SessionHandle {
+    fd: RawFd,
+}
+
+impl SessionHandle {
+    pub fn new(fd: RawFd) -> io::Result<Self> {
+        match unsafe { libc::dup(fd) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(Self { fd }),
+        }
+    }
+
+    /// Shuts the socket down, the session then fails to read.
+    pub fn shutdown(&self) {
+        unsafe { libc::shutdown(self.fd, libc::SHUT_RDWR) };
+    }
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weights_do_not_overflow() {
+        let mut pool = ServerPool::new(&[(0, u32::MAX), (0, u32::MAX), (0, u32::MAX), (1, 1)]);
+        for _ in 0..100 {
+            pool.current = None;
+            assert!(pool.select() < 3);
+        }
+    }
+
+    #[test]
+    fn fails_over_by_priority_and_skips_rejected() {
+        let mut pool = ServerPool::new(&[(0, 1), (1, 1), (2, 1)]);
+        assert_eq!(pool.select(), 0);
+        pool.failed(0);
+        assert_eq!(pool.select(), 1);
+        assert!(pool.rejected(1));
+        assert_eq!(pool.select(), 2);
+        assert_eq!(pool.preferred_over(2), vec![0]);
+
+        // all failed, the one which failed longest ago is retried, but not a rejected one
+        pool.failed(2);
+        assert_eq!(pool.select(), 0);
+        assert!(pool.rejected(0));
+        assert!(!pool.rejected(2));
+    }
+}
@@ -0,0 +1,222 @@
+use std::fs;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::anyhow;
+
+/// Marks the outer connection when no `--fwmark` is given, "tnl" in ASCII.
+pub const DEFAULT_MARK: u32 = 0x746e6c;
+
+/// Blocks the traffic of this host which does not go through the tunnel, with nftables,
+/// also while the client reconnects.
+///
+/// Allowed are the loopback and tun devices, the outer connection by its mark, DHCP,
+/// IPv6 neighbor discovery, and DNS to the nameservers, so the server's name can still be
+/// resolved while the tunnel is down. The nameservers are those in `/etc/resolv.conf`,
+/// the upstreams of systemd-resolved, and the given `dns_upstreams`.
+pub struct KillSwitch {
+    table: String,
+    installed: AtomicBool,
+}
+
+impl KillSwitch {
+    /// Replaces the rules left by a crashed run, if any.
+    pub fn install(tun_name: &str, mark: u32, dns_upstreams: &[SocketAddr]) -> io::Result<Self> {
+        let table = table_name(tun_name);
+        let mut allowed = nameservers(RESOLV_CONF).unwrap_or_else(|e| {
+            log::warn!(
+                "could not read the nameservers, DNS is blocked while the tunnel is down: {}",
+                e
+            );
+            Vec::new()
+        });
+        // behind systemd-resolved, /etc/resolv.conf names its stub on loopback only
+        match nameservers(RESOLVED_UPSTREAMS) {
+            Ok(upstreams) => allowed.extend(upstreams),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => log::warn!("could not read the systemd-resolved upstreams: {}", e),
+        }
+        allowed.extend_from_slice(dns_upstreams);
+        let ruleset = ruleset(&table, tun_name, mark, &allowed);
+        log::debug!("nft ruleset:\n{}", ruleset);
+        nft(&ruleset)?;
+        log::info!("kill switch on, traffic outside {} is blocked", tun_name);
+        Ok(Self {
+            table,
+            installed: AtomicBool::new(true),
+        })
+    }
+
+    /// Removes the rules, later calls do nothing.
+    pub fn remove(&self) {
+        if !self.installed.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        match nft(&format!("delete table inet {}\n", self.table)) {
+            Ok(()) => log::info!("kill switch off"),
+            Err(e) => log::warn!("could not remove the kill switch: {}", e),
+        }
+    }
+}
+
+/// Removes the rules of a client which did not remove them, e.g. after a crash.
+pub fn recover(tun_name: &str) -> io::Result<()> {
+    let table = table_name(tun_name);
+    // creating it first makes the deletion succeed when it is gone already
+    match nft(&format!("table inet {0}\ndelete table inet {0}\n", table)) {
+        // without nft there is no kill switch either
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// nftables identifiers are letters, digits and underscores here.
+fn table_name(tun_name: &str) -> String {
+    let name: String = tun_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("simple_tunnel_{}", name)
+}
+
+fn ruleset(table: &str, tun_name: &str, mark: u32, nameservers: &[SocketAddr]) -> String {
+    let mut rules = vec![
+        "oifname \"lo\" accept".to_string(),
+        format!("oifname \"{}\" accept", tun_name),
+        format!("meta mark {:#x} accept", mark),
+        // DHCP from the client port, DHCPv6 to the multicast address of the servers
+        "udp sport 68 udp dport 67 accept".to_string(),
+        "ip6 daddr ff02::1:2 udp sport 546 udp dport 547 accept".to_string(),
+        "icmpv6 type { nd-router-solicit, nd-neighbor-solicit, nd-neighbor-advert } accept"
+            .to_string(),
+    ];
+    let mut ports: Vec<u16> = nameservers.iter().map(|a| a.port()).collect();
+    ports.sort_unstable();
+    ports.dedup();
+    for port in ports {
+        for (family, ipv6) in &[("ip", false), ("ip6", true)] {
+            let mut addrs: Vec<IpAddr> = nameservers
+                .iter()
+                .filter(|a| a.port() == port && a.is_ipv6() == *ipv6)
+                .map(|a| a.ip())
+                .collect();
+            // nftables rejects duplicates in an anonymous set
+            addrs.sort_unstable();
+            addrs.dedup();
+            if addrs.is_empty() {
+                continue;
+            }
+            let addrs: Vec<String> = addrs.iter().map(|a| a.to_string()).collect();
+            let addrs = addrs.join(", ");
+            rules.push(format!(
+                "{} daddr {{ {} }} udp dport {} accept",
+                family, addrs, port
+            ));
+            rules.push(format!(
+                "{} daddr {{ {} }} tcp dport {} accept",
+                family, addrs, port
+            ));
+        }
+    }
+
+    let mut ruleset = format!(
+        "table inet {0}\ndelete table inet {0}\ntable inet {0} {{\n\tchain output {{\n\t\ttype filter hook output priority 0; policy drop;\n",
+        table
+    );
+    for rule in rules {
+        ruleset.push_str(&format!("\t\t{}\n", rule));
+    }
+    ruleset.push_str("\t}\n}\n");
+    ruleset
+}
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+/// The nameservers systemd-resolved forwards to, in the resolv.conf format.
+const RESOLVED_UPSTREAMS: &str = "/run/systemd/resolve/resolv.conf";
+
+/// The non-loopback nameservers in the resolv.conf file at `path`, loopback is allowed anyway.
+fn nameservers(path: &str) -> io::Result<Vec<SocketAddr>> {
+    let content = fs::read_to_string(path)?;
+    Ok(content
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        // an IPv6 nameserver may have a zone, which nftables does not take
+        .filter_map(|addr| addr.trim().split('%').next()?.parse::<IpAddr>().ok())
+        .filter(|addr| !addr.is_loopback())
+        .map(|addr| SocketAddr::new(addr, 53))
+        .collect())
+}
+
+/// Applies `ruleset` atomically with `nft -f -`.
+fn nft(ruleset: &str) -> io::Result<()> {
+    let mut child = Command::new("nft")
+        .args(["-f", "-"])
+        // spared by signals to the client's process group, e.g. Ctrl-C, while cleaning up
+        .process_group(0)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), anyhow!("could not run nft: {}", e)))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(ruleset.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(anyhow!(
+            "nft failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ruleset_allows_nameservers_by_port() {
+        let nameservers: Vec<SocketAddr> = vec![
+            "192.0.2.53:53".parse().unwrap(),
+            "192.0.2.54:53".parse().unwrap(),
+            "192.0.2.53:53".parse().unwrap(),
+            "[2001:db8::53]:53".parse().unwrap(),
+            "198.51.100.1:5353".parse().unwrap(),
+        ];
+        let ruleset = ruleset("simple_tunnel_tun0", "tun0", DEFAULT_MARK, &nameservers);
+        for rule in &[
+            "ip daddr { 192.0.2.53, 192.0.2.54 } udp dport 53 accept",
+            "ip daddr { 192.0.2.53, 192.0.2.54 } tcp dport 53 accept",
+            "ip6 daddr { 2001:db8::53 } udp dport 53 accept",
+            "ip daddr { 198.51.100.1 } udp dport 5353 accept",
+            "ip daddr { 198.51.100.1 } tcp dport 5353 accept",
+            "meta mark 0x746e6c accept",
+        ] {
+            assert!(ruleset.contains(rule), "{} in\n{}", rule, ruleset);
+        }
+        assert!(!ruleset.contains("ip6 daddr { 2001:db8::53 } udp dport 5353"));
+    }
+
+    #[test]
+    fn ruleset_allows_dhcp_only() {
+        let ruleset = ruleset("simple_tunnel_tun0", "tun0", DEFAULT_MARK, &[]);
+        let dhcp: Vec<&str> = ruleset
+            .lines()
+            .map(str::trim)
+            .filter(|rule| rule.contains("67") || rule.contains("547"))
+            .collect();
+        assert_eq!(
+            dhcp,
+            vec![
+                "udp sport 68 udp dport 67 accept",
+                "ip6 daddr ff02::1:2 udp sport 546 udp dport 547 accept",
+            ]
+        );
+    }
+}
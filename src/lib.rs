@@ -1,4 +1,12 @@
 mod poller;
 
+pub mod backoff;
+pub mod cert;
 pub mod datagram;
+pub mod dns;
+pub mod failover;
+pub mod killswitch;
+pub mod metrics;
+pub mod route;
+pub mod signal;
 pub mod sockets;
@@ -0,0 +1,48 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Gauges written to a file in the Prometheus text format,
+/// to be picked up by node_exporter's textfile collector.
+pub struct Metrics {
+    path: Option<PathBuf>,
+    gauges: Mutex<BTreeMap<String, f64>>,
+}
+
+impl Metrics {
+    /// Metrics are only kept in memory when `path` is `None`.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            gauges: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Sets a gauge, `key` is the metric name with optional labels,
+    /// e.g. `simple_tunnel_cert_expiring{cert="cert.pem"}`.
+    pub fn set(&self, key: &str, value: f64) {
+        let mut gauges = self.gauges.lock().unwrap();
+        gauges.insert(key.to_string(), value);
+        if let Err(e) = self.write(&gauges) {
+            log::warn!("could not write metrics: {}", e);
+        }
+    }
+
+    /// Writes to a temporary file then renames, so readers never see a partial file.
+    fn write(&self, gauges: &BTreeMap<String, f64>) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let mut content = String::new();
+        for (key, value) in gauges {
+            content.push_str(&format!("{} {}\n", key, value));
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, path)
+    }
+}
@@ -88,7 +88,7 @@ impl Poller {
             Some(t) if t == Duration::from_secs(0) => 0,
             Some(t) => {
                 // Round up to a whole millisecond.
-                let mut ms = t.as_millis().try_into().unwrap_or(std::i32::MAX);
+                let mut ms = t.as_millis().try_into().unwrap_or(i32::MAX);
                 if Duration::from_millis(ms as u64) < t {
                     ms = ms.saturating_add(1);
                 }
@@ -100,7 +100,7 @@ impl Poller {
         // Wait for I/O events.
         let res = syscall!(epoll_wait(
             self.epoll_fd,
-            self.events.list.as_mut_ptr() as *mut libc::epoll_event,
+            self.events.list.as_mut_ptr(),
             self.events.list.len() as libc::c_int,
             timeout_ms as libc::c_int,
         ))?;
@@ -0,0 +1,1022 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+
+/// Routes to the addresses of included domains last at least this long, as programs keep
+/// using answers and connections past the TTL.
+const MIN_DOMAIN_TTL: Duration = Duration::from_secs(300);
+
+/// At most this many addresses of included domains are routed at once, so answers with
+/// many or ever-changing addresses cannot grow the routing table without bound.
+const MAX_DOMAIN_ROUTES: usize = 4096;
+
+/// Where the routes installed for `tun_name` are kept, to remove them after a crash.
+pub fn state_path(tun_name: &str) -> PathBuf {
+    PathBuf::from(format!("/run/tunnel-{}.routes", tun_name))
+}
+
+/// An address block like `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// The whole address space as `0.0.0.0/1`, `128.0.0.0/1`, `::/1` and `8000::/1`,
+    /// which take precedence over a default route without replacing it.
+    pub fn everything() -> Vec<Self> {
+        vec![
+            Self::new(Ipv4Addr::UNSPECIFIED.into(), 1),
+            Self::new(Ipv4Addr::new(128, 0, 0, 0).into(), 1),
+            Self::new(Ipv6Addr::UNSPECIFIED.into(), 1),
+            Self::new(Ipv6Addr::new(0x8000, 0, 0, 0, 0, 0, 0, 0).into(), 1),
+        ]
+    }
+
+    /// Clears the bits of `addr` past the prefix, the kernel rejects routes with them set.
+    fn new(addr: IpAddr, prefix_len: u8) -> Self {
+        let addr = match addr {
+            IpAddr::V4(addr) => IpAddr::V4((u32::from(addr) & mask32(prefix_len)).into()),
+            IpAddr::V6(addr) => IpAddr::V6((u128::from(addr) & mask128(prefix_len)).into()),
+        };
+        Self { addr, prefix_len }
+    }
+
+    /// Parses `ADDR/LEN`, or a single address.
+    pub fn parse(s: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                anyhow!("invalid CIDR {:?}, expect ADDR/LEN or ADDR", s),
+            )
+        };
+        let (addr, prefix_len) = match s.trim().split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max_len = if addr.is_ipv6() { 128 } else { 32 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(Self::new(addr, prefix_len))
+    }
+
+    /// Reads one CIDR per line, `#` starts a comment.
+    pub fn load(path: &Path) -> io::Result<Vec<Self>> {
+        fs::read_to_string(path)?
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|line| !line.is_empty())
+            .map(Self::parse)
+            .collect()
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+                u32::from(addr) == u32::from(*ip) & mask32(self.prefix_len)
+            }
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                u128::from(addr) == u128::from(*ip) & mask128(self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+fn mask32(prefix_len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+}
+
+fn mask128(prefix_len: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0)
+}
+
+/// Which destinations go through the tunnel: those in `include` but not in `exclude`.
+#[derive(Clone, Debug, Default)]
+pub struct Split {
+    pub include: Vec<Cidr>,
+    pub exclude: Vec<Cidr>,
+    /// Accepted even when not included, e.g. the peer address of the tun device.
+    device_routes: Vec<Cidr>,
+    /// Addresses of included domains, accepted while `TunnelRoutes` routes them.
+    domain_addrs: Arc<RwLock<HashSet<IpAddr>>>,
+}
+
+impl Split {
+    pub fn new(include: Vec<Cidr>, exclude: Vec<Cidr>) -> Self {
+        Self {
+            include,
+            exclude,
+            device_routes: Vec::new(),
+            domain_addrs: Default::default(),
+        }
+    }
+
+    /// Accepts the destinations routed through `tun_name` already, e.g. by
+    /// `ip address add ... peer ...`.
+    pub fn allow_device_routes(&mut self, tun_name: &str) -> io::Result<()> {
+        let oif = if_index(tun_name)?;
+        for ipv6 in &[false, true] {
+            self.device_routes.extend(
+                list(*ipv6)?
+                    .into_iter()
+                    .filter(|(r, _)| r.oif == oif)
+                    .map(|(r, _)| r.cidr()),
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether `ip` goes through the tunnel, everything does without includes.
+    pub fn covers(&self, ip: &IpAddr) -> bool {
+        if self.exclude.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+        self.include.is_empty()
+            || self.include.iter().any(|c| c.contains(ip))
+            || self.device_routes.iter().any(|c| c.contains(ip))
+            || self.domain_addrs.read().unwrap().contains(ip)
+    }
+
+    /// Whether the IP packet read from the tun device is for a covered destination,
+    /// so a route which does not match the lists leaks nothing to the server.
+    pub fn accepts(&self, packet: &[u8]) -> bool {
+        let dst = match packet.first().map(|b| b >> 4) {
+            Some(4) if packet.len() >= 20 => {
+                IpAddr::from([packet[16], packet[17], packet[18], packet[19]])
+            }
+            Some(6) if packet.len() >= 40 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&packet[24..40]);
+                IpAddr::from(octets)
+            }
+            _ => return false,
+        };
+        let covered = self.covers(&dst);
+        if !covered {
+            log::debug!(
+                "drop packet to {}, it is not routed through the tunnel",
+                dst
+            );
+        }
+        covered
+    }
+}
+
+/// Routes the destinations of a `Split` through the tun device, except the connections
+/// to the servers.
+///
+/// The excluded blocks and a host route to each server address which an included block
+/// covers go through the way they take without the tunnel, e.g. the current gateway.
+/// The addresses of included domains get host routes through the tun device until they expire.
+pub struct TunnelRoutes {
+    tun_index: u32,
+    state_path: PathBuf,
+    split: Split,
+    installed: Mutex<Installed>,
+}
+
+#[derive(Default)]
+struct Installed {
+    /// The included and excluded blocks.
+    split: Vec<Route>,
+    /// Host routes by the server address they were resolved from.
+    exceptions: HashMap<String, Vec<Route>>,
+    /// Host routes to the addresses of included domains with their expiry.
+    domains: HashMap<IpAddr, (Route, Instant)>,
+}
+
+impl Installed {
+    fn routes(&self) -> impl Iterator<Item = &Route> {
+        self.exceptions
+            .values()
+            .flatten()
+            .chain(self.domains.values().map(|(r, _)| r))
+            .chain(&self.split)
+    }
+}
+
+impl TunnelRoutes {
+    /// Removes the routes left by a crashed run, then installs the routes of `split`.
+    pub fn install(tun_name: &str, state_path: PathBuf, split: &Split) -> io::Result<Self> {
+        recover(&state_path)?;
+        let tun_index = if_index(tun_name)?;
+        let routes = Self {
+            tun_index,
+            state_path,
+            split: split.clone(),
+            installed: Mutex::new(Installed::default()),
+        };
+        let mut installed = routes.installed.lock().unwrap();
+        let result = routes.install_split(&mut installed, split);
+        routes.save(&installed);
+        drop(installed);
+        if let Err(e) = result {
+            routes.restore();
+            return Err(e);
+        }
+        if let Some(include) = join(&split.include) {
+            log::info!("route {} through {}", include, tun_name);
+        }
+        if let Some(exclude) = join(&split.exclude) {
+            log::info!("keep {} out of the tunnel", exclude);
+        }
+        Ok(routes)
+    }
+
+    fn install_split(&self, installed: &mut Installed, split: &Split) -> io::Result<()> {
+        for cidr in &split.include {
+            let route = Route {
+                dst: cidr.addr,
+                prefix_len: cidr.prefix_len,
+                gateway: None,
+                oif: self.tun_index,
+            };
+            match add(&route) {
+                Ok(()) => installed.split.push(route),
+                // hosts without ipv6 on the tun device still work over ipv4
+                Err(e) if cidr.addr.is_ipv6() => {
+                    log::warn!(
+                        "could not add route {}, it bypasses the tunnel: {}",
+                        route,
+                        e
+                    )
+                }
+                Err(e) => {
+                    return Err(io::Error::other(anyhow!(
+                        "could not add route {}: {}",
+                        route,
+                        e
+                    )))
+                }
+            }
+        }
+
+        let routes = list(false)?.into_iter().chain(list(true)?);
+        let way_out: Vec<(Route, u32)> = routes.filter(|(r, _)| r.oif != self.tun_index).collect();
+        for cidr in &split.exclude {
+            let best = match best_route(&way_out, &cidr.addr, cidr.prefix_len) {
+                // e.g. the local network, which needs no route of its own
+                Some(best) if best.prefix_len == cidr.prefix_len => continue,
+                Some(best) => best,
+                None => {
+                    log::warn!("no route for {} outside the tunnel, it is dropped", cidr);
+                    continue;
+                }
+            };
+            let route = Route {
+                dst: cidr.addr,
+                prefix_len: cidr.prefix_len,
+                gateway: best.gateway,
+                oif: best.oif,
+            };
+            add(&route)
+                .map_err(|e| io::Error::other(anyhow!("could not add route {}: {}", route, e)))?;
+            installed.split.push(route);
+        }
+        Ok(())
+    }
+
+    /// Keeps the connections to `addrs`, which `host` resolves to, out of the tunnel,
+    /// replacing the host routes for earlier addresses of `host`.
+    pub fn route_outside(&self, host: &str, addrs: &[SocketAddr]) {
+        let mut installed = self.installed.lock().unwrap();
+        let routes = match list(false).and_then(|mut v4| {
+            v4.extend(list(true)?);
+            Ok(v4)
+        }) {
+            Ok(routes) => routes,
+            Err(e) => {
+                log::warn!(
+                    "could not list routes to keep {} outside the tunnel: {}",
+                    host,
+                    e
+                );
+                return;
+            }
+        };
+        // our own routes out of the tunnel are not the way out
+        let candidates: Vec<(Route, u32)> = routes
+            .into_iter()
+            .filter(|(r, _)| r.oif == self.tun_index || installed.routes().all(|i| i != r))
+            .collect();
+
+        let old = installed.exceptions.remove(host).unwrap_or_default();
+        let mut wanted: Vec<Route> = Vec::new();
+        for addr in addrs {
+            let route = match outside(addr.ip(), &candidates, self.tun_index) {
+                Some(route) => route,
+                None => continue,
+            };
+            if wanted.contains(&route) {
+                continue;
+            }
+            match replace(&route) {
+                Ok(()) if old.contains(&route) => wanted.push(route),
+                Ok(()) => {
+                    log::info!("route {} outside the tunnel for {}", route, host);
+                    wanted.push(route);
+                }
+                Err(e) => log::warn!("could not add route {}: {}", route, e),
+            }
+        }
+
+        for route in old {
+            let in_use = wanted.contains(&route) || installed.routes().any(|r| *r == route);
+            if !in_use {
+                log::info!("remove route {}, {} moved", route, host);
+                // fails when the new route replaced it already
+                let _ = delete(&route);
+            }
+        }
+        // the server goes outside the tunnel even when a domain resolves to it
+        for route in &wanted {
+            if installed.domains.remove(&route.dst).is_some() {
+                self.split.domain_addrs.write().unwrap().remove(&route.dst);
+            }
+        }
+        installed.exceptions.insert(host.to_string(), wanted);
+        self.save(&installed);
+    }
+
+    /// Routes `addrs`, which `name` of an included domain resolves to, through the tun device
+    /// until their TTLs expire, unless they are excluded or go through it already.
+    pub fn route_through(&self, name: &str, addrs: &[(IpAddr, u32)]) {
+        let mut installed = self.installed.lock().unwrap();
+        let now = Instant::now();
+        let mut changed = false;
+        for &(ip, ttl) in addrs {
+            let expiry = now + Duration::from_secs(ttl.into()).max(MIN_DOMAIN_TTL);
+            if let Some((_, expires)) = installed.domains.get_mut(&ip) {
+                *expires = (*expires).max(expiry);
+                continue;
+            }
+            let skip = ip.is_loopback()
+                || ip.is_unspecified()
+                || self.split.exclude.iter().any(|c| c.contains(&ip))
+                || self.split.include.iter().any(|c| c.contains(&ip))
+                || installed.exceptions.values().flatten().any(|r| r.dst == ip);
+            if skip {
+                continue;
+            }
+            if installed.domains.len() >= MAX_DOMAIN_ROUTES {
+                log::warn!(
+                    "{} addresses of included domains are routed already, {} stays outside the tunnel",
+                    MAX_DOMAIN_ROUTES,
+                    ip
+                );
+                continue;
+            }
+
+            let route = Route {
+                dst: ip,
+                prefix_len: if ip.is_ipv6() { 128 } else { 32 },
+                gateway: None,
+                oif: self.tun_index,
+            };
+            match add(&route) {
+                Ok(()) => {
+                    log::info!("route {} through the tunnel for {}", route, name);
+                    self.split.domain_addrs.write().unwrap().insert(ip);
+                    installed.domains.insert(ip, (route, expiry));
+                    changed = true;
+                }
+                // hosts without ipv6 on the tun device still work over ipv4
+                Err(e) if ip.is_ipv6() => log::debug!("could not add route {}: {}", route, e),
+                Err(e) => log::warn!("could not add route {}: {}", route, e),
+            }
+        }
+        if changed {
+            self.save(&installed);
+        }
+    }
+
+    /// Removes the routes of `route_through` whose TTL expired.
+    pub fn expire_domains(&self) {
+        let mut installed = self.installed.lock().unwrap();
+        let now = Instant::now();
+        let expired: Vec<IpAddr> = installed
+            .domains
+            .iter()
+            .filter(|(_, (_, expires))| *expires <= now)
+            .map(|(ip, _)| *ip)
+            .collect();
+        if expired.is_empty() {
+            return;
+        }
+        for ip in expired {
+            if let Some((route, _)) = installed.domains.remove(&ip) {
+                log::debug!("remove route {}, it expired", route);
+                if let Err(e) = delete(&route) {
+                    log::debug!("could not delete route {}: {}", route, e);
+                }
+            }
+            self.split.domain_addrs.write().unwrap().remove(&ip);
+        }
+        self.save(&installed);
+    }
+
+    /// Removes every installed route, they are not used any more.
+    pub fn restore(&self) {
+        let mut installed = self.installed.lock().unwrap();
+        for route in installed.routes() {
+            if let Err(e) = delete(route) {
+                log::debug!("could not delete route {}: {}", route, e);
+            }
+        }
+        *installed = Installed::default();
+        self.split.domain_addrs.write().unwrap().clear();
+        if let Err(e) = fs::remove_file(&self.state_path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                log::warn!("could not remove {:?}: {}", self.state_path, e);
+            }
+        }
+        log::info!("restored the routes");
+    }
+
+    /// Writes the installed routes for `recover`, through a temporary file.
+    fn save(&self, installed: &Installed) {
+        let mut content = String::new();
+        for route in installed.routes() {
+            content.push_str(&format!("{}\n", route));
+        }
+        let tmp = self.state_path.with_extension("tmp");
+        if let Err(e) = fs::write(&tmp, content).and_then(|_| fs::rename(&tmp, &self.state_path)) {
+            log::warn!("could not save routes to {:?}: {}", self.state_path, e);
+        }
+    }
+}
+
+/// Removes the routes saved in `state_path` by a run which did not restore them.
+pub fn recover(state_path: &Path) -> io::Result<()> {
+    let content = match fs::read_to_string(state_path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for line in content.lines().filter(|l| !l.trim().is_empty()) {
+        let route = line.parse::<Route>()?;
+        match delete(&route) {
+            Ok(()) => log::info!("removed route {} left by a previous run", route),
+            Err(e) => log::debug!("could not delete route {}: {}", route, e),
+        }
+    }
+    fs::remove_file(state_path)
+}
+
+fn join(cidrs: &[Cidr]) -> Option<String> {
+    if cidrs.is_empty() {
+        return None;
+    }
+    Some(
+        cidrs
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+    )
+}
+
+/// The host route keeping `ip` out of the tunnel, when the tunnel's routes would take it.
+fn outside(ip: IpAddr, routes: &[(Route, u32)], tun_index: u32) -> Option<Route> {
+    if ip.is_loopback() {
+        return None;
+    }
+    let full_len = if ip.is_ipv6() { 128 } else { 32 };
+    if best_route(routes, &ip, full_len)?.oif != tun_index {
+        return None;
+    }
+    let way_out: Vec<(Route, u32)> = routes
+        .iter()
+        .filter(|(r, _)| r.oif != tun_index)
+        .cloned()
+        .collect();
+    let best = best_route(&way_out, &ip, full_len)?;
+    Some(Route {
+        dst: ip,
+        prefix_len: full_len,
+        gateway: best.gateway,
+        oif: best.oif,
+    })
+}
+
+/// The most specific route to `ip` no more specific than `max_len`, by lowest metric among equals.
+fn best_route<'a>(routes: &'a [(Route, u32)], ip: &IpAddr, max_len: u8) -> Option<&'a Route> {
+    routes
+        .iter()
+        .filter(|(r, _)| r.prefix_len <= max_len && r.cidr().contains(ip))
+        .max_by_key(|(r, metric)| (r.prefix_len, u32::MAX - metric))
+        .map(|(r, _)| r)
+}
+
+/// A route in the main table, with what is needed to add and delete it again.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    pub dst: IpAddr,
+    pub prefix_len: u8,
+    pub gateway: Option<IpAddr>,
+    /// Index of the outgoing interface.
+    pub oif: u32,
+}
+
+impl Route {
+    fn cidr(&self) -> Cidr {
+        Cidr::new(self.dst, self.prefix_len)
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.dst, self.prefix_len)?;
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {}", gateway)?;
+        }
+        write!(f, " dev {}", self.oif)
+    }
+}
+
+impl FromStr for Route {
+    type Err = io::Error;
+
+    /// Parses the `Display` form, `DST/LEN [via GATEWAY] dev INDEX`.
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                anyhow!(
+                    "invalid route {:?}, expect DST/LEN [via GATEWAY] dev INDEX",
+                    s
+                ),
+            )
+        };
+        let mut words = s.split_whitespace();
+        let (dst, prefix_len) = words
+            .next()
+            .and_then(|w| w.split_once('/'))
+            .ok_or_else(invalid)?;
+        let mut route = Self {
+            dst: dst.parse().map_err(|_| invalid())?,
+            prefix_len: prefix_len.parse().map_err(|_| invalid())?,
+            gateway: None,
+            oif: 0,
+        };
+        while let Some(key) = words.next() {
+            let value = words.next().ok_or_else(invalid)?;
+            match key {
+                "via" => route.gateway = Some(value.parse().map_err(|_| invalid())?),
+                "dev" => route.oif = value.parse().map_err(|_| invalid())?,
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(route)
+    }
+}
+
+pub fn if_index(name: &str) -> io::Result<u32> {
+    let c_name = CString::new(name).map_err(io::Error::other)?;
+    match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+        0 => Err(io::Error::other(anyhow!(
+            "no interface {}: {}",
+            name,
+            io::Error::last_os_error()
+        ))),
+        index => Ok(index),
+    }
+}
+
+/// The unicast routes in the main table with their metric.
+pub fn list(ipv6: bool) -> io::Result<Vec<(Route, u32)>> {
+    let family = if ipv6 { libc::AF_INET6 } else { libc::AF_INET };
+    let mut body = vec![0; RTMSG_LEN];
+    body[0] = family as u8;
+    let messages = Netlink::open()?.request(libc::RTM_GETROUTE, libc::NLM_F_DUMP as u16, &body)?;
+
+    let mut routes = Vec::new();
+    for message in messages {
+        if message.len() < RTMSG_LEN || message[7] != libc::RTN_UNICAST {
+            continue;
+        }
+        let mut table = message[4] as u32;
+        let mut dst = None;
+        let mut gateway = None;
+        let mut oif = None;
+        let mut metric = 0;
+        for (kind, data) in attributes(&message[RTMSG_LEN..]) {
+            match kind {
+                libc::RTA_TABLE => table = read_u32(data).unwrap_or(table),
+                libc::RTA_DST => dst = read_ip(data),
+                libc::RTA_GATEWAY => gateway = read_ip(data),
+                libc::RTA_OIF => oif = read_u32(data),
+                libc::RTA_PRIORITY => metric = read_u32(data).unwrap_or(0),
+                _ => (),
+            }
+        }
+        // multipath routes have no single interface, they are not followed
+        let oif = match oif {
+            Some(oif) if table == libc::RT_TABLE_MAIN as u32 => oif,
+            _ => continue,
+        };
+        let unspecified = if ipv6 {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        };
+        routes.push((
+            Route {
+                dst: dst.unwrap_or(unspecified),
+                prefix_len: message[1],
+                gateway,
+                oif,
+            },
+            metric,
+        ));
+    }
+    Ok(routes)
+}
+
+/// Adds `route` to the main table, failing when one to the same destination exists.
+pub fn add(route: &Route) -> io::Result<()> {
+    request_route(route, libc::NLM_F_EXCL)
+}
+
+/// Adds `route` to the main table, replacing one to the same destination.
+pub fn replace(route: &Route) -> io::Result<()> {
+    request_route(route, libc::NLM_F_REPLACE)
+}
+
+fn request_route(route: &Route, flags: libc::c_int) -> io::Result<()> {
+    let scope = if route.gateway.is_some() {
+        libc::RT_SCOPE_UNIVERSE
+    } else {
+        libc::RT_SCOPE_LINK
+    };
+    let body = route_message(route, libc::RTPROT_STATIC, scope);
+    let flags = libc::NLM_F_CREATE | libc::NLM_F_ACK | flags;
+    Netlink::open()?.request(libc::RTM_NEWROUTE, flags as u16, &body)?;
+    Ok(())
+}
+
+pub fn delete(route: &Route) -> io::Result<()> {
+    let body = route_message(route, libc::RTPROT_UNSPEC, libc::RT_SCOPE_NOWHERE);
+    Netlink::open()?.request(libc::RTM_DELROUTE, libc::NLM_F_ACK as u16, &body)?;
+    Ok(())
+}
+
+/// Size of `struct rtmsg`, which starts a route message.
+const RTMSG_LEN: usize = 12;
+
+/// Builds a `struct rtmsg` followed by the attributes of `route`.
+fn route_message(route: &Route, protocol: u8, scope: u8) -> Vec<u8> {
+    let family = if route.dst.is_ipv6() {
+        libc::AF_INET6
+    } else {
+        libc::AF_INET
+    };
+    let mut message = vec![
+        family as u8,
+        route.prefix_len,
+        0,
+        0,
+        libc::RT_TABLE_MAIN,
+        protocol,
+        scope,
+        libc::RTN_UNICAST,
+        0,
+        0,
+        0,
+        0,
+    ];
+    if route.prefix_len > 0 {
+        push_attribute(&mut message, libc::RTA_DST, &ip_bytes(&route.dst));
+    }
+    if let Some(ref gateway) = route.gateway {
+        push_attribute(&mut message, libc::RTA_GATEWAY, &ip_bytes(gateway));
+    }
+    push_attribute(&mut message, libc::RTA_OIF, &route.oif.to_ne_bytes());
+    message
+}
+
+fn push_attribute(message: &mut Vec<u8>, kind: u16, data: &[u8]) {
+    let len = 4 + data.len();
+    message.extend_from_slice(&(len as u16).to_ne_bytes());
+    message.extend_from_slice(&kind.to_ne_bytes());
+    message.extend_from_slice(data);
+    message.resize(align(message.len()), 0);
+}
+
+/// Splits the attributes after the fixed part of a message into type and data.
+fn attributes(mut buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attributes = Vec::new();
+    while buf.len() >= 4 {
+        let len = u16::from_ne_bytes([buf[0], buf[1]]) as usize;
+        let kind = u16::from_ne_bytes([buf[2], buf[3]]);
+        if len < 4 || len > buf.len() {
+            break;
+        }
+        attributes.push((kind, &buf[4..len]));
+        buf = &buf[align(len).min(buf.len())..];
+    }
+    attributes
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn ip_bytes(ip: &IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn read_ip(data: &[u8]) -> Option<IpAddr> {
+    match data.len() {
+        4 => Some(IpAddr::from([data[0], data[1], data[2], data[3]])),
+        16 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(data);
+            Some(IpAddr::from(octets))
+        }
+        _ => None,
+    }
+}
+
+fn read_u32(data: &[u8]) -> Option<u32> {
+    let bytes: [u8; 4] = data.get(..4)?.try_into().ok()?;
+    Some(u32::from_ne_bytes(bytes))
+}
+
+/// A `NETLINK_ROUTE` socket.
+struct Netlink {
+    fd: RawFd,
+}
+
+/// Size of `struct nlmsghdr`.
+const NLMSGHDR_LEN: usize = 16;
+
+impl Netlink {
+    fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { fd })
+    }
+
+    /// Sends one request and returns the payloads of the replies,
+    /// until the end of a dump or the acknowledgement.
+    fn request(&self, kind: u16, flags: u16, body: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        const SEQ: u32 = 1;
+        let mut message = Vec::with_capacity(NLMSGHDR_LEN + body.len());
+        message.extend_from_slice(&((NLMSGHDR_LEN + body.len()) as u32).to_ne_bytes());
+        message.extend_from_slice(&kind.to_ne_bytes());
+        message.extend_from_slice(&(libc::NLM_F_REQUEST as u16 | flags).to_ne_bytes());
+        message.extend_from_slice(&SEQ.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(body);
+        let sent = unsafe {
+            libc::send(
+                self.fd,
+                message.as_ptr() as *const libc::c_void,
+                message.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut replies = Vec::new();
+        let mut buf = vec![0u8; 1 << 16];
+        loop {
+            let n =
+                unsafe { libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut received = &buf[..n as usize];
+            while received.len() >= NLMSGHDR_LEN {
+                let len = read_u32(received).unwrap_or(0) as usize;
+                let kind = u16::from_ne_bytes([received[4], received[5]]);
+                let seq = read_u32(&received[8..]).unwrap_or(0);
+                if len < NLMSGHDR_LEN || len > received.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        anyhow!("truncated netlink message"),
+                    ));
+                }
+                let payload = &received[NLMSGHDR_LEN..len];
+                received = &received[align(len).min(received.len())..];
+                if seq != SEQ {
+                    continue;
+                }
+
+                match kind as libc::c_int {
+                    libc::NLMSG_DONE => return Ok(replies),
+                    libc::NLMSG_ERROR => {
+                        let errno = read_u32(payload).map_or(0, |e| e as i32);
+                        return match errno {
+                            0 => Ok(replies),
+                            errno => Err(io::Error::from_raw_os_error(-errno)),
+                        };
+                    }
+                    _ => replies.push(payload.to_vec()),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Netlink {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidrs(list: &[&str]) -> Vec<Cidr> {
+        list.iter().map(|s| Cidr::parse(s).unwrap()).collect()
+    }
+
+    #[test]
+    fn parse_cidrs() {
+        for &(input, parsed) in &[
+            ("10.0.0.0/8", "10.0.0.0/8"),
+            (" 10.1.2.3/8 ", "10.0.0.0/8"),
+            ("192.0.2.1", "192.0.2.1/32"),
+            ("192.0.2.1/32", "192.0.2.1/32"),
+            ("255.255.255.255/0", "0.0.0.0/0"),
+            ("2001:db8::1/32", "2001:db8::/32"),
+            ("2001:db8::1", "2001:db8::1/128"),
+            ("2001:db8::1/128", "2001:db8::1/128"),
+            ("ffff::/0", "::/0"),
+            ("::ffff:192.0.2.1/120", "::ffff:192.0.2.0/120"),
+        ] {
+            assert_eq!(Cidr::parse(input).unwrap().to_string(), parsed, "{}", input);
+        }
+
+        for input in &[
+            "",
+            "10.0.0.0/",
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "10.0.0.0/-1",
+            "10.0.0/8",
+            "example.com/8",
+            "10.0.0.0/8/8",
+        ] {
+            let e = Cidr::parse(input).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{}", input);
+        }
+    }
+
+    #[test]
+    fn cidr_contains() {
+        for &(cidr, addr, contained) in &[
+            ("10.0.0.0/8", "10.255.255.255", true),
+            ("10.0.0.0/8", "11.0.0.0", false),
+            ("10.1.2.3/8", "10.9.9.9", true),
+            ("192.0.2.1/32", "192.0.2.1", true),
+            ("192.0.2.1/32", "192.0.2.2", false),
+            ("0.0.0.0/0", "203.0.113.9", true),
+            ("0.0.0.0/0", "2001:db8::1", false),
+            ("2001:db8::/32", "2001:db8:ffff::1", true),
+            ("2001:db8::/32", "2001:db9::1", false),
+            ("2001:db8::1/128", "2001:db8::1", true),
+            ("2001:db8::1/128", "2001:db8::2", false),
+            ("::/0", "2001:db8::1", true),
+            ("::/0", "192.0.2.1", false),
+            // an IPv4-mapped address is IPv6
+            ("192.0.2.0/24", "::ffff:192.0.2.1", false),
+        ] {
+            assert_eq!(
+                Cidr::parse(cidr).unwrap().contains(&ip(addr)),
+                contained,
+                "{} contains {}",
+                cidr,
+                addr
+            );
+        }
+    }
+
+    #[test]
+    fn everything_covers_both_families() {
+        let everything = Cidr::everything();
+        for addr in &[
+            "0.0.0.0",
+            "127.255.255.255",
+            "128.0.0.0",
+            "255.255.255.255",
+            "::",
+            "7fff::1",
+            "8000::",
+            "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff",
+        ] {
+            let matching = everything.iter().filter(|c| c.contains(&ip(addr))).count();
+            assert_eq!(matching, 1, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn split_covers() {
+        // without includes everything goes through, but the excludes
+        let split = Split::new(Vec::new(), cidrs(&["192.168.0.0/16", "fd00::/8"]));
+        for &(addr, covered) in &[
+            ("203.0.113.9", true),
+            ("2001:db8::1", true),
+            ("192.168.1.1", false),
+            ("fd00::1", false),
+        ] {
+            assert_eq!(split.covers(&ip(addr)), covered, "{}", addr);
+        }
+
+        // excludes win over includes, also wider includes and host includes
+        let split = Split::new(
+            cidrs(&["10.0.0.0/8", "0.0.0.0/0", "192.0.2.1/32", "2001:db8::/32"]),
+            cidrs(&["10.1.0.0/16", "192.0.2.1", "2001:db8::1/128"]),
+        );
+        for &(addr, covered) in &[
+            ("10.2.0.1", true),
+            ("10.1.0.1", false),
+            ("203.0.113.9", true),
+            ("192.0.2.1", false),
+            ("2001:db8::2", true),
+            ("2001:db8::1", false),
+            ("2001:db9::1", false),
+        ] {
+            assert_eq!(split.covers(&ip(addr)), covered, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn split_covers_domain_addresses_unless_excluded() {
+        let split = Split::new(cidrs(&["10.0.0.0/8"]), cidrs(&["192.0.2.0/24"]));
+        assert!(!split.covers(&ip("203.0.113.9")));
+
+        let mut domain_addrs = split.domain_addrs.write().unwrap();
+        domain_addrs.insert(ip("203.0.113.9"));
+        domain_addrs.insert(ip("192.0.2.9"));
+        drop(domain_addrs);
+        assert!(split.covers(&ip("203.0.113.9")));
+        assert!(!split.covers(&ip("192.0.2.9")));
+    }
+
+    #[test]
+    fn split_accepts_packets_by_destination() {
+        let split = Split::new(cidrs(&["10.0.0.0/8", "2001:db8::/32"]), Vec::new());
+
+        let mut ipv4 = [0u8; 20];
+        ipv4[0] = 0x45;
+        ipv4[16..20].copy_from_slice(&[10, 0, 0, 1]);
+        assert!(split.accepts(&ipv4));
+        ipv4[16] = 11;
+        assert!(!split.accepts(&ipv4));
+        assert!(!split.accepts(&ipv4[..19]));
+
+        let mut ipv6 = [0u8; 40];
+        ipv6[0] = 0x60;
+        ipv6[24..40].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        assert!(split.accepts(&ipv6));
+        ipv6[24] = 0x30;
+        assert!(!split.accepts(&ipv6));
+        assert!(!split.accepts(&[]));
+    }
+}
@@ -0,0 +1,54 @@
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static HANGUPS: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_hangup(_signum: libc::c_int) {
+    HANGUPS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Counts SIGHUP instead of terminating the process.
+pub fn handle_hangup() -> io::Result<()> {
+    let handler: extern "C" fn(libc::c_int) = on_hangup;
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = handler as libc::sighandler_t;
+    action.sa_flags = libc::SA_RESTART;
+    match unsafe { libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut()) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// The number of SIGHUP received so far.
+/// Compare with an earlier value to know whether a new one arrived.
+pub fn hangups() -> usize {
+    HANGUPS.load(Ordering::SeqCst)
+}
+
+/// Runs `cleanup` on SIGINT or SIGTERM, then lets the signal terminate the process.
+/// Call it before spawning threads, they inherit the blocked signals.
+pub fn on_terminate<F: FnOnce() + Send + 'static>(cleanup: F) -> io::Result<()> {
+    let mut signals: libc::sigset_t = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+    }
+    match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut()) } {
+        0 => (),
+        e => return Err(io::Error::from_raw_os_error(e)),
+    }
+
+    std::thread::spawn(move || {
+        let mut signum = 0;
+        if unsafe { libc::sigwait(&signals, &mut signum) } != 0 {
+            return;
+        }
+        cleanup();
+        unsafe {
+            libc::pthread_sigmask(libc::SIG_UNBLOCK, &signals, std::ptr::null_mut());
+            libc::raise(signum);
+        }
+    });
+    Ok(())
+}
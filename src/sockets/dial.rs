@@ -0,0 +1,413 @@
+use std::io;
+use std::mem;
+use std::net::{self, IpAddr, SocketAddr, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+
+use super::proxy::Proxy;
+
+/// Called with a server or proxy address and what it resolves to, before connecting.
+pub type Resolved = dyn Fn(&str, &[SocketAddr]) + Send + Sync;
+
+/// Opens the outer TCP connection to the server.
+#[derive(Clone)]
+pub struct Dialer {
+    /// Connect through the proxy instead of directly.
+    pub proxy: Option<Proxy>,
+    /// Give up connecting directly after this long, across all addresses.
+    pub connect_timeout: Duration,
+    /// Start connecting to the next address after this long while the previous
+    /// attempts go on, as in Happy Eyeballs (RFC 8305).
+    pub attempt_delay: Duration,
+    /// Applied to the socket to the server or to the proxy.
+    pub socket_options: SocketOptions,
+    /// E.g. to route the addresses around the tunnel.
+    pub on_resolve: Option<Arc<Resolved>>,
+}
+
+impl Default for Dialer {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            connect_timeout: Duration::from_secs(10),
+            attempt_delay: Duration::from_millis(250),
+            socket_options: SocketOptions::default(),
+            on_resolve: None,
+        }
+    }
+}
+
+impl Dialer {
+    /// Connects to `addr`, which is `host:port`.
+    pub fn dial(&self, addr: &str) -> io::Result<net::TcpStream> {
+        let tcp_stream = match self.proxy {
+            Some(ref proxy) => {
+                log::debug!("connect to {} through {:?}", addr, proxy);
+                let tcp_stream = proxy
+                    .connect(addr, &|proxy_addr| {
+                        let tcp_stream = self.connect(proxy_addr)?;
+                        // a proxy which stops answering must not stall the handshake
+                        tcp_stream.set_read_timeout(Some(self.connect_timeout))?;
+                        tcp_stream.set_write_timeout(Some(self.connect_timeout))?;
+                        Ok(tcp_stream)
+                    })
+                    .map_err(|e| match e.kind() {
+                        io::ErrorKind::WouldBlock => io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            anyhow!("proxy did not answer within {:?}", self.connect_timeout),
+                        ),
+                        _ => e,
+                    })?;
+                tcp_stream.set_read_timeout(None)?;
+                tcp_stream.set_write_timeout(None)?;
+                tcp_stream
+            }
+            None => self.connect(addr)?,
+        };
+        tcp_stream.set_nodelay(true)?;
+
+        Ok(tcp_stream)
+    }
+
+    /// Runs `handshake` on `tcp_stream`, dialed to `addr`, with read and write timeouts of
+    /// `connect_timeout`, so a server which stops answering cannot stall the handshake.
+    pub fn handshake<T>(
+        &self,
+        addr: &str,
+        tcp_stream: net::TcpStream,
+        handshake: impl FnOnce(net::TcpStream) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let timeouts = tcp_stream.try_clone()?;
+        timeouts.set_read_timeout(Some(self.connect_timeout))?;
+        timeouts.set_write_timeout(Some(self.connect_timeout))?;
+        let result = handshake(tcp_stream).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock => io::Error::new(
+                io::ErrorKind::TimedOut,
+                anyhow!(
+                    "handshake with {} did not complete within {:?}",
+                    addr,
+                    self.connect_timeout
+                ),
+            ),
+            _ => e,
+        })?;
+        timeouts.set_read_timeout(None)?;
+        timeouts.set_write_timeout(None)?;
+
+        Ok(result)
+    }
+
+    /// Races the addresses `addr` resolves to, the first connected one wins.
+    fn connect(&self, addr: &str) -> io::Result<net::TcpStream> {
+        let deadline = Instant::now() + self.connect_timeout;
+        // resolved on every dial, so a server behind dynamic DNS is found at its new address
+        let addrs = interleave(
+            addr.to_socket_addrs()?
+                .filter(|a| self.socket_options.can_reach(a))
+                .collect(),
+        );
+        if addrs.is_empty() {
+            let e = match self.socket_options.source_address {
+                Some(source) => anyhow!("{} has no address reachable from {}", addr, source),
+                None => anyhow!("{} does not resolve to any address", addr),
+            };
+            return Err(io::Error::new(io::ErrorKind::NotFound, e));
+        }
+        log::debug!("{} resolves to {:?}", addr, addrs);
+        if let Some(ref on_resolve) = self.on_resolve {
+            on_resolve(addr, &addrs);
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let mut addrs = addrs.into_iter();
+        let mut pending = 0;
+        let mut last_error = None;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                break;
+            }
+            if let Some(sockaddr) = addrs.next() {
+                let tx = tx.clone();
+                let options = self.socket_options.clone();
+                thread::spawn(move || {
+                    let result = options.connect(&sockaddr, remaining);
+                    // the receiver is gone when another address won
+                    let _ = tx.send((sockaddr, result));
+                });
+                pending += 1;
+            }
+            if pending == 0 {
+                break;
+            }
+
+            let wait = match addrs.len() {
+                0 => remaining,
+                _ => self.attempt_delay.min(remaining),
+            };
+            match rx.recv_timeout(wait) {
+                Ok((sockaddr, Ok(tcp_stream))) => {
+                    log::info!("connected to {} at {}", addr, sockaddr);
+                    return Ok(tcp_stream);
+                }
+                Ok((sockaddr, Err(e))) => {
+                    log::debug!("could not connect to {} at {}: {}", addr, sockaddr, e);
+                    last_error = Some(e);
+                    pending -= 1;
+                }
+                Err(_) => (),
+            }
+        }
+
+        match (pending, last_error) {
+            (0, Some(e)) => Err(e),
+            _ => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                anyhow!(
+                    "could not connect to {} within {:?}",
+                    addr,
+                    self.connect_timeout
+                ),
+            )),
+        }
+    }
+}
+
+/// Alternates the address families, starting with the resolver's first choice
+/// and keeping its order within each family.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_ipv6 = addrs.first().is_some_and(|a| a.is_ipv6());
+    let (preferred, other): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_ipv6);
+
+    let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return interleaved,
+            (a, b) => {
+                interleaved.extend(a);
+                interleaved.extend(b);
+            }
+        }
+    }
+}
+
+/// Options of the outer socket, so policy routing can keep it out of the tunnel.
+#[derive(Clone, Default)]
+pub struct SocketOptions {
+    /// Send through this interface only, with `SO_BINDTODEVICE`.
+    pub bind_interface: Option<String>,
+    /// Connect from this local address, only addresses of its family are tried.
+    pub source_address: Option<IpAddr>,
+    /// Mark the packets with `SO_MARK`, for e.g. `ip rule add fwmark`.
+    pub fwmark: Option<u32>,
+}
+
+impl SocketOptions {
+    fn can_reach(&self, addr: &SocketAddr) -> bool {
+        self.source_address
+            .map_or(true, |source| source.is_ipv6() == addr.is_ipv6())
+    }
+
+    fn connect(&self, addr: &SocketAddr, timeout: Duration) -> io::Result<net::TcpStream> {
+        if self.bind_interface.is_none() && self.source_address.is_none() && self.fwmark.is_none() {
+            return net::TcpStream::connect_timeout(addr, timeout);
+        }
+
+        let family = match addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+        let fd = unsafe {
+            libc::socket(
+                family,
+                libc::SOCK_STREAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // owns the socket from here on, to close it on errors
+        let tcp_stream = unsafe { net::TcpStream::from_raw_fd(fd) };
+
+        if let Some(ref interface) = self.bind_interface {
+            setsockopt(fd, libc::SO_BINDTODEVICE, interface.as_bytes()).map_err(|e| {
+                io::Error::other(anyhow!("could not bind to interface {}: {}", interface, e))
+            })?;
+        }
+        if let Some(mark) = self.fwmark {
+            setsockopt(fd, libc::SO_MARK, &mark.to_ne_bytes())
+                .map_err(|e| io::Error::other(anyhow!("could not set fwmark {}: {}", mark, e)))?;
+        }
+        if let Some(source) = self.source_address {
+            let (sockaddr, len) = raw_sockaddr(&SocketAddr::new(source, 0));
+            if unsafe { libc::bind(fd, &sockaddr as *const _ as *const libc::sockaddr, len) } < 0 {
+                let e = io::Error::last_os_error();
+                return Err(io::Error::other(anyhow!(
+                    "could not bind to {}: {}",
+                    source,
+                    e
+                )));
+            }
+        }
+
+        let (sockaddr, len) = raw_sockaddr(addr);
+        if unsafe { libc::connect(fd, &sockaddr as *const _ as *const libc::sockaddr, len) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EINPROGRESS) {
+                return Err(e);
+            }
+            wait_connected(&tcp_stream, timeout)?;
+        }
+        tcp_stream.set_nonblocking(false)?;
+        Ok(tcp_stream)
+    }
+}
+
+/// Waits for a non-blocking connect to finish.
+fn wait_connected(tcp_stream: &net::TcpStream, timeout: Duration) -> io::Result<()> {
+    let mut pollfd = libc::pollfd {
+        fd: tcp_stream.as_raw_fd(),
+        events: libc::POLLOUT,
+        revents: 0,
+    };
+    let timeout_ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+        n if n < 0 => return Err(io::Error::last_os_error()),
+        0 => return Err(io::Error::from(io::ErrorKind::TimedOut)),
+        _ => (),
+    }
+    match tcp_stream.take_error()? {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn setsockopt(fd: libc::c_int, name: libc::c_int, value: &[u8]) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            name,
+            value.as_ptr() as *const libc::c_void,
+            value.len() as libc::socklen_t,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+pub(crate) fn raw_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            unsafe { (&mut storage as *mut _ as *mut libc::sockaddr_in).write(sin) };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            unsafe { (&mut storage as *mut _ as *mut libc::sockaddr_in6).write(sin6) };
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn interleave_alternates_families() {
+        let addrs = |list: &[&str]| -> Vec<SocketAddr> {
+            list.iter().map(|a| a.parse().unwrap()).collect()
+        };
+        for (input, expected) in &[
+            (
+                addrs(&[
+                    "[2001:db8::1]:443",
+                    "[2001:db8::2]:443",
+                    "192.0.2.1:443",
+                    "192.0.2.2:443",
+                ]),
+                addrs(&[
+                    "[2001:db8::1]:443",
+                    "192.0.2.1:443",
+                    "[2001:db8::2]:443",
+                    "192.0.2.2:443",
+                ]),
+            ),
+            (
+                addrs(&[
+                    "192.0.2.1:443",
+                    "[2001:db8::1]:443",
+                    "[2001:db8::2]:443",
+                    "[2001:db8::3]:443",
+                ]),
+                addrs(&[
+                    "192.0.2.1:443",
+                    "[2001:db8::1]:443",
+                    "[2001:db8::2]:443",
+                    "[2001:db8::3]:443",
+                ]),
+            ),
+            (
+                addrs(&["192.0.2.1:443", "192.0.2.2:443", "192.0.2.3:443"]),
+                addrs(&["192.0.2.1:443", "192.0.2.2:443", "192.0.2.3:443"]),
+            ),
+            (
+                addrs(&["[2001:db8::2]:443", "[2001:db8::1]:443"]),
+                addrs(&["[2001:db8::2]:443", "[2001:db8::1]:443"]),
+            ),
+            (vec![], vec![]),
+        ] {
+            assert_eq!(interleave(input.clone()), *expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn silent_server_times_out_handshake() {
+        // accepts the connection, but never answers
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let dialer = Dialer {
+            connect_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+
+        let tcp_stream = dialer.dial(&addr).unwrap();
+        let e = dialer
+            .handshake(&addr, tcp_stream, |mut tcp_stream| {
+                tcp_stream.read_exact(&mut [0u8; 1])
+            })
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        drop(listener);
+    }
+}
@@ -0,0 +1,948 @@
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use ring::{hkdf, hmac};
+use rustls::{CipherSuite, KeyLog, ProtocolVersion, Session};
+
+// include/uapi/linux/tls.h and tcp.h
+const TCP_ULP: libc::c_int = 31;
+const SOL_TLS: libc::c_int = 282;
+const TLS_TX: libc::c_int = 1;
+const TLS_RX: libc::c_int = 2;
+const TLS_SET_RECORD_TYPE: libc::c_int = 1;
+const TLS_GET_RECORD_TYPE: libc::c_int = 2;
+const TLS_1_2_VERSION: u16 = 0x0303;
+const TLS_1_3_VERSION: u16 = 0x0304;
+const TLS_CIPHER_AES_GCM_128: u16 = 51;
+const TLS_CIPHER_AES_GCM_256: u16 = 52;
+
+// TLS record content types
+const CHANGE_CIPHER_SPEC: u8 = 20;
+const ALERT: u8 = 21;
+const HANDSHAKE: u8 = 22;
+const APPLICATION_DATA: u8 = 23;
+
+const CLIENT_HELLO: u8 = 1;
+const SERVER_HELLO: u8 = 2;
+const KEY_UPDATE: u8 = 24;
+
+/// Post-handshake messages longer than this end the connection.
+const MAX_HANDSHAKE_MESSAGE: usize = 1 << 16;
+
+/// The kernel's `tls12_crypto_info_aes_gcm_128` and `_256`, which differ in the key size only.
+#[repr(C)]
+struct CryptoInfo<K> {
+    version: u16,
+    cipher_type: u16,
+    iv: [u8; 8],
+    key: K,
+    salt: [u8; 4],
+    rec_seq: [u8; 8],
+}
+
+/// Either rustls or, after a successful `offload`, the kernel encrypts the records.
+pub enum TlsStream<S: Session> {
+    Rustls(rustls::StreamOwned<S, TcpStream>),
+    Kernel(KernelTlsStream),
+}
+
+impl<S: Session> TlsStream<S> {
+    pub fn get_ref(&self) -> &TcpStream {
+        match self {
+            TlsStream::Rustls(stream) => stream.get_ref(),
+            TlsStream::Kernel(stream) => &stream.tcp_stream,
+        }
+    }
+
+    pub fn is_kernel(&self) -> bool {
+        matches!(self, TlsStream::Kernel(_))
+    }
+}
+
+impl<S: Session> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            TlsStream::Rustls(stream) => stream.read(buf),
+            TlsStream::Kernel(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl<S: Session> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            TlsStream::Rustls(stream) => stream.write(buf),
+            TlsStream::Kernel(stream) => stream.tcp_stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TlsStream::Rustls(stream) => stream.flush(),
+            TlsStream::Kernel(stream) => stream.tcp_stream.flush(),
+        }
+    }
+}
+
+/// A TCP stream with the `tls` ULP, the kernel encrypts what is written and decrypts what
+/// is read.
+pub struct KernelTlsStream {
+    tcp_stream: TcpStream,
+    /// Plaintext rustls decrypted before the offload, read first.
+    pending: Vec<u8>,
+    rx: DirectionKeys,
+    tx: DirectionKeys,
+    /// Post-handshake messages received in part.
+    handshake: Vec<u8>,
+}
+
+impl Read for KernelTlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.pending.is_empty() {
+            let n = buf.len().min(self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.drain(..n);
+            return Ok(n);
+        }
+
+        loop {
+            let (n, record_type) = self.recv_record(buf)?;
+            match record_type {
+                APPLICATION_DATA => return Ok(n),
+                HANDSHAKE => self.handshake_messages(&buf[..n])?,
+                ALERT if n >= 2 && buf[1] == 0 => return Ok(0), // close_notify
+                ALERT => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        anyhow!("received tls alert {}", buf.get(1).copied().unwrap_or(0)),
+                    ))
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        anyhow!("unexpected tls record type {}", record_type),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+impl KernelTlsStream {
+    /// Handles the post-handshake messages in `data`, which may span records.
+    fn handshake_messages(&mut self, data: &[u8]) -> io::Result<()> {
+        self.handshake.extend_from_slice(data);
+        while self.handshake.len() >= 4 {
+            let h = &self.handshake;
+            let len = u32::from_be_bytes([0, h[1], h[2], h[3]]) as usize;
+            if len > MAX_HANDSHAKE_MESSAGE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    anyhow!("tls handshake message of {} bytes is too long", len),
+                ));
+            }
+            if h.len() < 4 + len {
+                break;
+            }
+            let message: Vec<u8> = self.handshake.drain(..4 + len).collect();
+            match message[0] {
+                KEY_UPDATE => self.key_update(&message[4..])?,
+                // e.g. NewSessionTicket, which is of no use without rustls
+                typ => log::debug!("ignore post-handshake message {} of kernel tls", typ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Switches to the peer's next keys for receiving and, when the peer asks for it,
+    /// to the next keys for sending after telling the peer.
+    fn key_update(&mut self, body: &[u8]) -> io::Result<()> {
+        let update_requested = match body {
+            [0] => false,
+            [1] => true,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    anyhow!("invalid tls key update"),
+                ))
+            }
+        };
+        let fd = self.tcp_stream.as_raw_fd();
+
+        let rx = self.rx.next()?;
+        rx.set(fd, TLS_RX).map_err(|e| {
+            io::Error::other(anyhow!(
+                "could not update the kernel tls receiving keys: {}",
+                e
+            ))
+        })?;
+        self.rx = rx;
+
+        if update_requested {
+            // with the current keys still
+            self.send_record(HANDSHAKE, &[KEY_UPDATE, 0, 0, 1, 0])?;
+            let tx = self.tx.next()?;
+            tx.set(fd, TLS_TX).map_err(|e| {
+                io::Error::other(anyhow!(
+                    "could not update the kernel tls sending keys: {}",
+                    e
+                ))
+            })?;
+            self.tx = tx;
+        }
+        log::debug!("updated kernel tls keys, sending too: {}", update_requested);
+        Ok(())
+    }
+
+    /// Sends `data` as one record of `record_type`, which the kernel takes from a control
+    /// message.
+    fn send_record(&mut self, record_type: u8, data: &[u8]) -> io::Result<()> {
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut control = [0u64; 8];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(1) } as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = SOL_TLS;
+            (*cmsg).cmsg_type = TLS_SET_RECORD_TYPE;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(1) as _;
+            *libc::CMSG_DATA(cmsg) = record_type;
+        }
+
+        let n = unsafe { libc::sendmsg(self.tcp_stream.as_raw_fd(), &msg, 0) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if n as usize != data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                anyhow!("sent {} of {} bytes of a tls record", n, data.len()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Receives one record's plaintext, the type of records other than application
+    /// data comes in a control message.
+    fn recv_record(&mut self, buf: &mut [u8]) -> io::Result<(usize, u8)> {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut control = [0u64; 8];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let n = unsafe { libc::recvmsg(self.tcp_stream.as_raw_fd(), &mut msg, 0) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut record_type = APPLICATION_DATA;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == SOL_TLS && (*cmsg).cmsg_type == TLS_GET_RECORD_TYPE {
+                    record_type = *libc::CMSG_DATA(cmsg);
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        Ok((n as usize, record_type))
+    }
+}
+
+/// Keeps the secrets rustls logs, to hand them to the kernel, and passes them on to
+/// `inner`, e.g. a `KeyLogFile`.
+pub(crate) struct SecretLog {
+    inner: Arc<dyn KeyLog>,
+    /// Secrets of the latest handshakes.
+    secrets: Mutex<Vec<LoggedSecret>>,
+}
+
+struct LoggedSecret {
+    label: String,
+    client_random: Vec<u8>,
+    secret: Vec<u8>,
+}
+
+impl SecretLog {
+    /// At most this many secrets are kept, three per handshake, `offload` takes them
+    /// right away.
+    const CAPACITY: usize = 48;
+
+    pub fn new(inner: Arc<dyn KeyLog>) -> Self {
+        Self {
+            inner,
+            secrets: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn take(&self, label: &str, client_random: &[u8]) -> Option<Vec<u8>> {
+        let mut secrets = self.secrets.lock().unwrap();
+        let i = secrets
+            .iter()
+            .position(|s| s.label == label && s.client_random == client_random)?;
+        Some(secrets.remove(i).secret)
+    }
+}
+
+impl KeyLog for SecretLog {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        self.inner.log(label, client_random, secret);
+
+        match label {
+            "CLIENT_RANDOM" | "CLIENT_TRAFFIC_SECRET_0" | "SERVER_TRAFFIC_SECRET_0" => (),
+            _ => return,
+        }
+        let mut secrets = self.secrets.lock().unwrap();
+        if secrets.len() >= Self::CAPACITY {
+            secrets.remove(0);
+        }
+        secrets.push(LoggedSecret {
+            label: label.to_string(),
+            client_random: client_random.to_vec(),
+            secret: secret.to_vec(),
+        });
+    }
+}
+
+/// Follows the TLS records going through a stream during the handshake, to know the
+/// sequence numbers and the hello randoms the kernel needs.
+pub(crate) struct RecordTap<'a, T> {
+    inner: &'a mut T,
+    read: RecordCounter,
+    written: RecordCounter,
+}
+
+impl<'a, T> RecordTap<'a, T> {
+    pub fn new(inner: &'a mut T) -> Self {
+        Self {
+            inner,
+            read: RecordCounter::default(),
+            written: RecordCounter::default(),
+        }
+    }
+
+    pub fn into_records(self) -> HandshakeRecords {
+        HandshakeRecords {
+            read: self.read,
+            written: self.written,
+        }
+    }
+}
+
+/// What `RecordTap` saw of a handshake.
+pub(crate) struct HandshakeRecords {
+    read: RecordCounter,
+    written: RecordCounter,
+}
+
+impl<'a, T: Read> Read for RecordTap<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read.feed(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<'a, T: Write> Write for RecordTap<'a, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written.feed(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[derive(Default)]
+struct RecordCounter {
+    header: Vec<u8>,
+    /// Type and remaining body length of the current record.
+    current: Option<(u8, usize)>,
+    /// Start of the body of the current handshake record, up to the hello random.
+    body: Vec<u8>,
+    hello_random: Option<[u8; 32]>,
+    change_cipher_spec: bool,
+    /// Records after ChangeCipherSpec, which TLS 1.2 encrypts.
+    after_change_cipher_spec: u64,
+    application_data: u64,
+}
+
+impl RecordCounter {
+    fn feed(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let (typ, remaining) = match self.current {
+                Some(current) => current,
+                None => {
+                    let n = (5 - self.header.len()).min(data.len());
+                    self.header.extend_from_slice(&data[..n]);
+                    data = &data[n..];
+                    if self.header.len() < 5 {
+                        return;
+                    }
+                    let len = u16::from_be_bytes([self.header[3], self.header[4]]) as usize;
+                    let typ = self.header[0];
+                    self.header.clear();
+                    self.body.clear();
+                    self.current = Some((typ, len));
+                    (typ, len)
+                }
+            };
+
+            let n = remaining.min(data.len());
+            if typ == HANDSHAKE && !self.change_cipher_spec && self.hello_random.is_none() {
+                let wanted = (38 - self.body.len()).min(n);
+                self.body.extend_from_slice(&data[..wanted]);
+                // type, length, version, random
+                if self.body.len() == 38 && matches!(self.body[0], CLIENT_HELLO | SERVER_HELLO) {
+                    let mut random = [0u8; 32];
+                    random.copy_from_slice(&self.body[6..38]);
+                    self.hello_random = Some(random);
+                }
+            }
+            data = &data[n..];
+
+            if n == remaining {
+                self.current = None;
+                match typ {
+                    CHANGE_CIPHER_SPEC => self.change_cipher_spec = true,
+                    _ if self.change_cipher_spec => self.after_change_cipher_spec += 1,
+                    _ => (),
+                }
+                if typ == APPLICATION_DATA {
+                    self.application_data += 1;
+                }
+            } else {
+                self.current = Some((typ, remaining - n));
+            }
+        }
+    }
+
+    fn at_boundary(&self) -> bool {
+        self.current.is_none() && self.header.is_empty()
+    }
+}
+
+/// Completes the handshake, following the records when they are to be offloaded.
+pub(crate) fn complete_handshake<S: Session>(
+    session: &mut S,
+    tcp_stream: &mut TcpStream,
+    kernel_tls: bool,
+) -> io::Result<Option<HandshakeRecords>> {
+    if !kernel_tls {
+        session.complete_io(tcp_stream)?;
+        return Ok(None);
+    }
+
+    let mut tap = RecordTap::new(tcp_stream);
+    session.complete_io(&mut tap)?;
+    // e.g. the client's Finished, rustls sends it along with the first data otherwise
+    while session.wants_write() {
+        session.write_tls(&mut tap)?;
+    }
+    Ok(Some(tap.into_records()))
+}
+
+/// Hands the record encryption of an established session to the kernel.
+///
+/// `records` must cover the whole handshake, with nothing left to write. Falls back to
+/// rustls when the kernel lacks the `tls` module or the cipher suite is not AES-GCM,
+/// an error means the connection is unusable.
+pub(crate) fn offload<S: Session>(
+    mut session: S,
+    tcp_stream: TcpStream,
+    records: &HandshakeRecords,
+    secrets: &SecretLog,
+    is_client: bool,
+) -> io::Result<TlsStream<S>> {
+    let fallback = |session, tcp_stream, reason: &dyn std::fmt::Display| {
+        log::info!("keep tls in userspace: {}", reason);
+        Ok(TlsStream::Rustls(rustls::StreamOwned::new(
+            session, tcp_stream,
+        )))
+    };
+
+    let (rx, tx) = match traffic_keys(&session, records, secrets, is_client) {
+        Ok(keys) => keys,
+        Err(reason) => return fallback(session, tcp_stream, &reason),
+    };
+
+    // once the kernel receives there is no going back to rustls, so sending must work too
+    if let Err(e) = can_send(&tx) {
+        return fallback(
+            session,
+            tcp_stream,
+            &format!("kernel tls cannot send: {}", e),
+        );
+    }
+
+    // a ULP without keys passes everything through, so rustls can still go on
+    let fd = tcp_stream.as_raw_fd();
+    if let Err(e) = setsockopt(fd, libc::SOL_TCP, TCP_ULP, b"tls") {
+        return fallback(session, tcp_stream, &format!("no kernel tls: {}", e));
+    }
+    // the receiving side first, old kernels only offload sending
+    if let Err(e) = rx.set(fd, TLS_RX) {
+        return fallback(
+            session,
+            tcp_stream,
+            &format!("kernel tls cannot receive: {}", e),
+        );
+    }
+    tx.set(fd, TLS_TX)
+        .map_err(|e| io::Error::other(anyhow!("could not offload sending to kernel tls: {}", e)))?;
+
+    let mut pending = Vec::new();
+    session
+        .read_to_end(&mut pending)
+        .or_else(|e| match e.kind() {
+            io::ErrorKind::ConnectionAborted => Ok(0),
+            _ => Err(e),
+        })?;
+
+    log::info!("offloaded tls to the kernel");
+    Ok(TlsStream::Kernel(KernelTlsStream {
+        tcp_stream,
+        pending,
+        rx,
+        tx,
+        handshake: Vec::new(),
+    }))
+}
+
+/// Keys of one direction in the kernel's terms.
+struct DirectionKeys {
+    version: u16,
+    key: Vec<u8>,
+    salt: [u8; 4],
+    iv: [u8; 8],
+    seq: u64,
+    /// The TLS 1.3 traffic secret the keys come from, for the next ones.
+    secret: Option<Vec<u8>>,
+}
+
+impl DirectionKeys {
+    /// The keys after a TLS 1.3 KeyUpdate.
+    fn next(&self) -> io::Result<DirectionKeys> {
+        let secret = self.secret.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                anyhow!("tls key update without tls 1.3"),
+            )
+        })?;
+        let sha384 = secret.len() == 48;
+        let algorithm = if sha384 {
+            hkdf::HKDF_SHA384
+        } else {
+            hkdf::HKDF_SHA256
+        };
+        let prk = hkdf::Prk::new_less_safe(algorithm, secret);
+        let next = hkdf_expand_label(&prk, b"traffic upd", secret.len());
+        Ok(tls13_keys(&next, self.key.len(), sha384, 0))
+    }
+
+    fn set(&self, fd: libc::c_int, direction: libc::c_int) -> io::Result<()> {
+        let rec_seq = self.seq.to_be_bytes();
+        match self.key.len() {
+            16 => {
+                let mut key = [0u8; 16];
+                key.copy_from_slice(&self.key);
+                let info = CryptoInfo {
+                    version: self.version,
+                    cipher_type: TLS_CIPHER_AES_GCM_128,
+                    iv: self.iv,
+                    key,
+                    salt: self.salt,
+                    rec_seq,
+                };
+                setsockopt(fd, SOL_TLS, direction, as_bytes(&info))
+            }
+            _ => {
+                let mut key = [0u8; 32];
+                key.copy_from_slice(&self.key);
+                let info = CryptoInfo {
+                    version: self.version,
+                    cipher_type: TLS_CIPHER_AES_GCM_256,
+                    iv: self.iv,
+                    key,
+                    salt: self.salt,
+                    rec_seq,
+                };
+                setsockopt(fd, SOL_TLS, direction, as_bytes(&info))
+            }
+        }
+    }
+}
+
+/// A version and key size, with whether the kernel can send with them.
+type SendSupport = ((u16, usize), Result<(), String>);
+
+/// Whether the kernel can send with keys of this version and size, tried once on a
+/// loopback connection.
+fn can_send(keys: &DirectionKeys) -> Result<(), String> {
+    static TRIED: Mutex<Vec<SendSupport>> = Mutex::new(Vec::new());
+
+    let kind = (keys.version, keys.key.len());
+    let mut tried = TRIED.lock().unwrap();
+    if let Some((_, result)) = tried.iter().find(|(k, _)| *k == kind) {
+        return result.clone();
+    }
+
+    let try_send = || -> io::Result<()> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let tcp_stream = TcpStream::connect(listener.local_addr()?)?;
+        let fd = tcp_stream.as_raw_fd();
+        setsockopt(fd, libc::SOL_TCP, TCP_ULP, b"tls")?;
+        keys.set(fd, TLS_TX)
+    };
+    let result = try_send().map_err(|e| e.to_string());
+    tried.push((kind, result.clone()));
+    result
+}
+
+/// Derives the receiving and sending keys with the sequence numbers of the next records.
+fn traffic_keys<S: Session>(
+    session: &S,
+    tap: &HandshakeRecords,
+    secrets: &SecretLog,
+    is_client: bool,
+) -> Result<(DirectionKeys, DirectionKeys), String> {
+    if !tap.read.at_boundary() || !tap.written.at_boundary() {
+        return Err("the handshake ended within a record".to_string());
+    }
+    let (client_random, server_random) = if is_client {
+        (tap.written.hello_random, tap.read.hello_random)
+    } else {
+        (tap.read.hello_random, tap.written.hello_random)
+    };
+    let client_random = client_random.ok_or("no client hello seen")?;
+
+    let suite = session
+        .get_negotiated_ciphersuite()
+        .ok_or("no cipher suite negotiated")?
+        .suite;
+    let (key_len, sha384) = match suite {
+        CipherSuite::TLS13_AES_128_GCM_SHA256
+        | CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256
+        | CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256 => (16, false),
+        CipherSuite::TLS13_AES_256_GCM_SHA384
+        | CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384
+        | CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384 => (32, true),
+        _ => return Err(format!("cipher suite {:?} is not AES-GCM", suite)),
+    };
+
+    let (client, server) = match session.get_protocol_version() {
+        Some(ProtocolVersion::TLSv1_3) => {
+            let client_secret = secrets
+                .take("CLIENT_TRAFFIC_SECRET_0", &client_random)
+                .ok_or("no client traffic secret logged")?;
+            let server_secret = secrets
+                .take("SERVER_TRAFFIC_SECRET_0", &client_random)
+                .ok_or("no server traffic secret logged")?;
+            // Only the client's Finished is encrypted with handshake keys after the
+            // server's flight, and the server sends nothing before it. Session tickets
+            // after the handshake would be counted by neither side, so kernel tls
+            // servers do not send them.
+            let client_seq = if is_client {
+                0
+            } else {
+                tap.read.application_data.saturating_sub(1)
+            };
+            let client = tls13_keys(&client_secret, key_len, sha384, client_seq);
+            let server = tls13_keys(&server_secret, key_len, sha384, 0);
+            (client, server)
+        }
+        Some(ProtocolVersion::TLSv1_2) => {
+            let server_random = server_random.ok_or("no server hello seen")?;
+            let master = secrets
+                .take("CLIENT_RANDOM", &client_random)
+                .ok_or("no master secret logged")?;
+            // records are encrypted from ChangeCipherSpec on, starting with Finished
+            let (client_seq, server_seq) = if is_client {
+                (
+                    tap.written.after_change_cipher_spec,
+                    tap.read.after_change_cipher_spec,
+                )
+            } else {
+                (
+                    tap.read.after_change_cipher_spec,
+                    tap.written.after_change_cipher_spec,
+                )
+            };
+            tls12_keys(
+                &master,
+                &client_random,
+                &server_random,
+                key_len,
+                sha384,
+                (client_seq, server_seq),
+            )
+        }
+        version => return Err(format!("unsupported version {:?}", version)),
+    };
+
+    Ok(if is_client {
+        (server, client)
+    } else {
+        (client, server)
+    })
+}
+
+fn tls13_keys(secret: &[u8], key_len: usize, sha384: bool, seq: u64) -> DirectionKeys {
+    let algorithm = if sha384 {
+        hkdf::HKDF_SHA384
+    } else {
+        hkdf::HKDF_SHA256
+    };
+    let prk = hkdf::Prk::new_less_safe(algorithm, secret);
+    let key = hkdf_expand_label(&prk, b"key", key_len);
+    let iv = hkdf_expand_label(&prk, b"iv", 12);
+
+    let mut salt = [0u8; 4];
+    salt.copy_from_slice(&iv[..4]);
+    let mut explicit = [0u8; 8];
+    explicit.copy_from_slice(&iv[4..]);
+    DirectionKeys {
+        version: TLS_1_3_VERSION,
+        key,
+        salt,
+        iv: explicit,
+        seq,
+        secret: Some(secret.to_vec()),
+    }
+}
+
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// HKDF-Expand-Label of RFC 8446 with an empty context.
+fn hkdf_expand_label(prk: &hkdf::Prk, label: &[u8], len: usize) -> Vec<u8> {
+    let output_len = (len as u16).to_be_bytes();
+    let label_len = [6 + label.len() as u8];
+    let info: &[&[u8]] = &[&output_len, &label_len, b"tls13 ", label, &[0]];
+
+    let mut out = vec![0u8; len];
+    prk.expand(info, Len(len))
+        .and_then(|okm| okm.fill(&mut out))
+        .expect("valid hkdf output length");
+    out
+}
+
+fn tls12_keys(
+    master: &[u8],
+    client_random: &[u8],
+    server_random: &[u8],
+    key_len: usize,
+    sha384: bool,
+    (client_seq, server_seq): (u64, u64),
+) -> (DirectionKeys, DirectionKeys) {
+    let algorithm = if sha384 {
+        hmac::HMAC_SHA384
+    } else {
+        hmac::HMAC_SHA256
+    };
+    let seed = [server_random, client_random].concat();
+    // client key, server key, client salt, server salt
+    let block = prf(algorithm, master, b"key expansion", &seed, 2 * key_len + 8);
+    let (client_key, rest) = block.split_at(key_len);
+    let (server_key, rest) = rest.split_at(key_len);
+
+    let keys = |key: &[u8], salt: &[u8], seq: u64| {
+        let mut s = [0u8; 4];
+        s.copy_from_slice(salt);
+        DirectionKeys {
+            version: TLS_1_2_VERSION,
+            key: key.to_vec(),
+            salt: s,
+            // the explicit nonce, which the kernel increments per record
+            iv: seq.to_be_bytes(),
+            seq,
+            secret: None,
+        }
+    };
+    (
+        keys(client_key, &rest[..4], client_seq),
+        keys(server_key, &rest[4..8], server_seq),
+    )
+}
+
+/// The PRF of RFC 5246, P_hash with the suite's hash.
+fn prf(
+    algorithm: hmac::Algorithm,
+    secret: &[u8],
+    label: &[u8],
+    seed: &[u8],
+    len: usize,
+) -> Vec<u8> {
+    let key = hmac::Key::new(algorithm, secret);
+    let seed = [label, seed].concat();
+
+    let mut out = Vec::with_capacity(len);
+    let mut a = hmac::sign(&key, &seed);
+    while out.len() < len {
+        let mut ctx = hmac::Context::with_key(&key);
+        ctx.update(a.as_ref());
+        ctx.update(&seed);
+        out.extend_from_slice(ctx.sign().as_ref());
+        a = hmac::sign(&key, a.as_ref());
+    }
+    out.truncate(len);
+    out
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+fn setsockopt(
+    fd: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &[u8],
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value.as_ptr() as *const libc::c_void,
+            value.len() as libc::socklen_t,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn tls13_keys_of_rfc8448() {
+        // the server handshake and application traffic secrets of RFC 8448, section 3
+        let keys = tls13_keys(
+            &hex("b67b7d690cc16c4e75e54213cb2d37b4e9c912bcded9105d42befd59d391ad38"),
+            16,
+            false,
+            0,
+        );
+        assert_eq!(keys.version, TLS_1_3_VERSION);
+        assert_eq!(keys.key, hex("3fce516009c21727d0f2e4e86ee403bc"));
+        assert_eq!(keys.salt[..], hex("5d313eb2")[..]);
+        assert_eq!(keys.iv[..], hex("671276ee13000b30")[..]);
+
+        let keys = tls13_keys(
+            &hex("a11af9f05531f856ad47116b45a950328204b4f44bfb6b3a4b4f1f3fcb631643"),
+            16,
+            false,
+            7,
+        );
+        assert_eq!(keys.key, hex("9f02283b6c9c07efc26bb9f2ac92e356"));
+        assert_eq!(keys.salt[..], hex("cf782b88")[..]);
+        assert_eq!(keys.iv[..], hex("dd83549aadf1e984")[..]);
+        assert_eq!(keys.seq, 7);
+    }
+
+    #[test]
+    fn prf_sha256() {
+        // the widely used test vector of the TLS 1.2 PRF with SHA-256
+        let out = prf(
+            hmac::HMAC_SHA256,
+            &hex("9bbe436ba940f017b17652849a71db35"),
+            b"test label",
+            &hex("a0ba9f936cda311827a6f796ffd5198c"),
+            100,
+        );
+        assert_eq!(
+            out,
+            hex(concat!(
+                "e3f229ba727be17b8d122620557cd453c2aab21d07c3d495329b52d4e61edb5a",
+                "6b301791e90d35c9c9a46b4e14baf9af0fa022f7077def17abfd3797c0564bab",
+                "4fbc91666e9def9b97fce34f796789baa48082d122ee42c5a72e5a5110fff701",
+                "87347b66",
+            ))
+        );
+    }
+
+    #[test]
+    fn tls12_keys_split_the_key_block() {
+        let master: Vec<u8> = (0..48).collect();
+        let client_random = [0xc0; 32];
+        let server_random = [0x5e; 32];
+
+        let (client, server) =
+            tls12_keys(&master, &client_random, &server_random, 16, false, (1, 2));
+        assert_eq!(client.version, TLS_1_2_VERSION);
+        assert_eq!(client.key, hex("ab9bd9302bd13d4e1ccd095472af4a9b"));
+        assert_eq!(server.key, hex("08f5e7e9b329fab7b7058f60d60aabe6"));
+        assert_eq!(client.salt[..], hex("1751c365")[..]);
+        assert_eq!(server.salt[..], hex("fd6e3b3f")[..]);
+        assert_eq!((client.seq, client.iv), (1, 1u64.to_be_bytes()));
+        assert_eq!((server.seq, server.iv), (2, 2u64.to_be_bytes()));
+
+        let (client, server) =
+            tls12_keys(&master, &client_random, &server_random, 32, true, (0, 0));
+        assert_eq!(
+            client.key,
+            hex("03f8e5f647b0e22a1beee277f64233b14dbd296246ee82c82cbb6bcc4d6401f5")
+        );
+        assert_eq!(
+            server.key,
+            hex("6f8da9b2cd474e2bfad3c6d87957457f1be8d180c4b07899a14a3c4387740b9a")
+        );
+        assert_eq!(client.salt[..], hex("e89040a1")[..]);
+        assert_eq!(server.salt[..], hex("a73553ec")[..]);
+        assert!(client.next().is_err());
+    }
+
+    #[test]
+    fn next_keys_after_key_update() {
+        let keys = tls13_keys(
+            &hex("a11af9f05531f856ad47116b45a950328204b4f44bfb6b3a4b4f1f3fcb631643"),
+            16,
+            false,
+            7,
+        );
+        let next = keys.next().unwrap();
+        assert_eq!(
+            next.secret.unwrap(),
+            hex("51921b8aa3001976eb401d0a4319a8516416a6c56001a357e5d162031e84f916")
+        );
+        assert_eq!(next.key, hex("2e63be99d67b39097feb9786cf7a15a0"));
+        assert_eq!(next.salt[..], hex("628a0a82")[..]);
+        assert_eq!(next.iv[..], hex("98ac953baef4255a")[..]);
+        assert_eq!(next.seq, 0);
+    }
+}
@@ -1,2 +1,6 @@
+pub mod dial;
+pub mod ktls;
+pub mod proxy;
 pub mod read_write;
+pub mod tls;
 pub mod websocket;
@@ -1,38 +1,80 @@
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
+use std::collections::HashMap;
 use std::fs;
 use std::io;
-use std::io::Seek;
 use std::net;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener as StdUnixListener, UnixStream};
+use std::path::Path;
+use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
 use tungstenite::{
     accept_hdr, client,
     handshake::server::{Callback, ErrorResponse, Request, Response},
+    handshake::HandshakeError,
     http, Error, Message, WebSocket,
 };
 
 use rustls;
 use rustls::Session;
 use webpki;
-use webpki_roots;
 
 use crate::datagram::{Rx, Tx};
+use crate::dns::DnsConfig;
+
+use super::dial::Dialer;
+use super::ktls::{self, SecretLog, TlsStream};
+use super::tls::{
+    self, CountingTicketer, CountingVerifier, FileSessionCache, PinnedVerifier, SpkiPin, TlsPolicy,
+    TrustStore,
+};
+
+/// The server refused the credentials, or the client the server certificate, so
+/// connecting to the same server again would not help.
+#[derive(Debug)]
+pub struct Rejected(String);
+
+impl Rejected {
+    /// Whether `e` is a rejection, rather than e.g. a network or proxy failure.
+    pub fn is(e: &io::Error) -> bool {
+        matches!(e.get_ref(), Some(e) if e.is::<Rejected>())
+    }
+}
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
 
 pub struct Socket<T> {
     web_socket: WebSocket<T>,
+    /// Pushed by the server, empty on the server side.
+    dns: DnsConfig,
 }
 
+impl<T> Socket<T> {
+    /// The DNS settings the server pushed in the handshake.
+    pub fn dns(&self) -> &DnsConfig {
+        &self.dns
+    }
+}
+
+/// Response headers of the upgrade carrying `DnsConfig`, comma separated lists.
+const DNS_HEADER: &str = "x-tunnel-dns";
+const DNS_SEARCH_HEADER: &str = "x-tunnel-dns-search";
+
 impl<T: io::Write + io::Read> Rx for Socket<T> {
     fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         loop {
             let m = self.web_socket.read_message().map_err(|e| match e {
                 Error::Io(e) if e.kind() == io::ErrorKind::WouldBlock => e,
-                _ => io::Error::new(
-                    io::ErrorKind::Other,
-                    anyhow!("could not read message: {}", e),
-                ),
+                _ => io::Error::other(anyhow!("could not read message: {}", e)),
             })?;
             let received = match m {
                 Message::Binary(received) => received,
@@ -62,10 +104,7 @@ impl<T: io::Write + io::Read> Tx for Socket<T> {
             .or_else(|e| match e {
                 // ignore WouldBlock, because in that case, write_message will still queue the message.
                 Error::Io(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
-                _ => Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    anyhow!("could not write message: {}", e),
-                )),
+                _ => Err(io::Error::other(anyhow!("could not write message: {}", e))),
             })?;
 
         Ok(buf.len())
@@ -74,88 +113,356 @@ impl<T: io::Write + io::Read> Tx for Socket<T> {
     fn flush(&mut self) -> io::Result<()> {
         self.web_socket.write_pending().map_err(|e| match e {
             Error::Io(e) if e.kind() == io::ErrorKind::WouldBlock => e,
-            _ => io::Error::new(
-                io::ErrorKind::Other,
-                anyhow!("could not write pending: {}", e),
-            ),
+            _ => io::Error::other(anyhow!("could not write pending: {}", e)),
         })
     }
 }
 
-impl<S: Session> AsRawFd for Socket<rustls::StreamOwned<S, net::TcpStream>> {
+impl<S: Session> AsRawFd for Socket<TlsStream<S>> {
     fn as_raw_fd(&self) -> RawFd {
         let tcp_stream = self.web_socket.get_ref().get_ref();
         tcp_stream.as_raw_fd()
     }
 }
 
+impl<S: Session> Socket<TlsStream<S>> {
+    /// Whether the kernel encrypts the records, see `TlsPolicy::enable_kernel_tls`.
+    pub fn is_kernel_tls(&self) -> bool {
+        self.web_socket.get_ref().is_kernel()
+    }
+}
+
+impl AsRawFd for Socket<net::TcpStream> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.web_socket.get_ref().as_raw_fd()
+    }
+}
+
+impl AsRawFd for Socket<UnixStream> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.web_socket.get_ref().as_raw_fd()
+    }
+}
+
 pub struct TlsTcpListener {
     listener: net::TcpListener,
     tls_config: Arc<rustls::ServerConfig>,
-    auth: BasicAuthentication,
+    ticketer: Arc<CountingTicketer>,
+    secrets: Option<Arc<SecretLog>>,
+    handshake: ServerHandshake,
 }
 
 impl TlsTcpListener {
+    /// `certs` chooses the certificate for each handshake,
+    /// e.g. `CertificateFiles` which may be reloaded meanwhile.
     pub fn new(
         listener: net::TcpListener,
-        cert_path: &str,
-        key_path: &str,
-        auth: BasicAuthentication,
-    ) -> io::Result<Self> {
-        let certs = load_certs(&cert_path)?;
-        let keys = load_private_keys(&key_path)?;
-        if keys.is_empty() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                anyhow!("file {:} does not contain any private key", &key_path),
-            ));
-        }
+        certs: Arc<dyn rustls::ResolvesServerCert>,
+        policy: &TlsPolicy,
+        handshake: ServerHandshake,
+    ) -> Self {
         let mut tls_config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
-        tls_config
-            .set_single_cert(certs, keys[0].clone())
-            .or_else(|e| {
-                Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    anyhow!("could not set single cert: {:?}", e),
-                ))
-            })?;
+        tls_config.cert_resolver = certs;
+        policy.apply_server(&mut tls_config);
 
-        Ok(Self {
+        // session ticket keys rotate every 6 hours, the previous key still decrypts
+        let ticketer = Arc::new(CountingTicketer::new(rustls::Ticketer::new()));
+        if policy.session_tickets() {
+            tls_config.ticketer = ticketer.clone();
+        }
+
+        let secrets = secret_log(policy, &mut tls_config.key_log);
+
+        Self {
             listener,
             tls_config: Arc::new(tls_config),
-            auth,
-        })
+            ticketer,
+            secrets,
+            handshake,
+        }
     }
 
-    pub fn accept(
-        &self,
-    ) -> io::Result<Socket<rustls::StreamOwned<rustls::ServerSession, net::TcpStream>>> {
-        let (mut tcp_stream, _addr) = self.listener.accept()?;
+    pub fn accept(&self) -> io::Result<Socket<TlsStream<rustls::ServerSession>>> {
+        let (mut tcp_stream, addr) = self.listener.accept()?;
         tcp_stream.set_nodelay(true)?;
 
         let mut tls_session = rustls::ServerSession::new(&self.tls_config);
-        tls_session.complete_io(&mut tcp_stream)?;
+        let decrypted = self.ticketer.decrypted();
+        let records =
+            ktls::complete_handshake(&mut tls_session, &mut tcp_stream, self.secrets.is_some())?;
+        let resumed = match tls_session.get_protocol_version() {
+            Some(rustls::ProtocolVersion::TLSv1_3) => {
+                tls_session.received_resumption_data().is_some()
+            }
+            _ => self.ticketer.decrypted() != decrypted,
+        };
+        tls::log_session(&addr, &tls_session, resumed);
+
+        let sni = tls_session.get_sni_hostname().map(|s| s.to_string());
+        let tls_stream = match (records, &self.secrets) {
+            (Some(records), Some(secrets)) => {
+                ktls::offload(tls_session, tcp_stream, &records, secrets, false)?
+            }
+            _ => TlsStream::Rustls(rustls::StreamOwned::new(tls_session, tcp_stream)),
+        };
+
+        self.handshake.accept(tls_stream, Some(addr.ip()), sni)
+    }
+}
 
-        let tls_stream = rustls::StreamOwned::new(tls_session, tcp_stream);
+/// Accepts plain WebSocket connections over TCP,
+/// for running behind a reverse proxy which terminates TLS.
+pub struct TcpListener {
+    listener: net::TcpListener,
+    handshake: ServerHandshake,
+}
 
-        let callback = AutherizationCallback(self.auth.autherization());
-        let web_socket = accept_hdr(tls_stream, callback).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                anyhow!("could not accept websocket: {}", e),
-            )
+impl TcpListener {
+    pub fn new(listener: net::TcpListener, handshake: ServerHandshake) -> Self {
+        Self {
+            listener,
+            handshake,
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<Socket<net::TcpStream>> {
+        let (tcp_stream, addr) = self.listener.accept()?;
+        tcp_stream.set_nodelay(true)?;
+
+        self.handshake.accept(tcp_stream, Some(addr.ip()), None)
+    }
+}
+
+/// Accepts plain WebSocket connections over a Unix domain socket,
+/// for running behind a reverse proxy which terminates TLS.
+pub struct UnixListener {
+    listener: StdUnixListener,
+    handshake: ServerHandshake,
+}
+
+impl UnixListener {
+    /// Binds to `path`, removing a stale socket file left by a previous run.
+    pub fn bind<P: AsRef<Path>>(path: P, handshake: ServerHandshake) -> io::Result<Self> {
+        let path = path.as_ref();
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let listener = StdUnixListener::bind(path)?;
+
+        Ok(Self {
+            listener,
+            handshake,
+        })
+    }
+
+    pub fn accept(&self) -> io::Result<Socket<UnixStream>> {
+        let (unix_stream, _addr) = self.listener.accept()?;
+
+        self.handshake.accept(unix_stream, None, None)
+    }
+}
+
+/// Options of the server side WebSocket handshake.
+pub struct HandshakeOptions {
+    /// The request path clients must upgrade on, others get 404.
+    pub path: String,
+    /// Take the client address from the `X-Forwarded-For` or `X-Real-IP` header
+    /// set by the reverse proxy.
+    pub trust_forwarded: bool,
+    /// The number of failed authentications allowed per client address within one minute,
+    /// before further attempts are rejected without checking.
+    pub max_auth_failures: usize,
+    /// Headers which must be present with exactly these values, others get 403.
+    pub required_headers: http::HeaderMap,
+    /// Users of virtual hosts, by lower case host name.
+    /// The virtual host is the SNI, or the `Host` header without TLS.
+    /// Other hosts accept the default users.
+    pub vhost_users: HashMap<String, Users>,
+    /// Pushed to the clients in the upgrade response.
+    pub dns: DnsConfig,
+    /// Pushed to these users instead of `dns`, by username.
+    pub user_dns: HashMap<String, DnsConfig>,
+}
+
+/// The WebSocket handshake shared by all kinds of listeners.
+pub struct ServerHandshake {
+    users: Users,
+    options: HandshakeOptions,
+    limiter: Mutex<RateLimiter>,
+    /// Whether a client from loopback was warned about, it is once.
+    warned_loopback: AtomicBool,
+}
+
+impl ServerHandshake {
+    /// `users` are accepted on hosts without their own users.
+    pub fn new(users: Users, options: HandshakeOptions) -> Self {
+        let limiter = RateLimiter::new(Duration::from_secs(60), options.max_auth_failures);
+        Self {
+            users,
+            options,
+            limiter: Mutex::new(limiter),
+            warned_loopback: AtomicBool::new(false),
+        }
+    }
+
+    fn accept<S: io::Read + io::Write>(
+        &self,
+        stream: S,
+        peer: Option<net::IpAddr>,
+        sni: Option<String>,
+    ) -> io::Result<Socket<S>> {
+        // behind a reverse proxy, every client would have the proxy's address
+        if !self.options.trust_forwarded
+            && peer.is_some_and(|peer| peer.is_loopback())
+            && !self.warned_loopback.swap(true, Ordering::SeqCst)
+        {
+            log::warn!(
+                "client connects from loopback, e.g. through a reverse proxy: without trusting \
+                 its forwarded headers, all its clients share one limit of {} failed \
+                 authentications a minute",
+                self.options.max_auth_failures
+            );
+        }
+        let mut client = ClientInfo {
+            addr: peer,
+            vhost: sni,
+            username: None,
+        };
+        let callback = AutherizationCallback {
+            users: &self.users,
+            options: &self.options,
+            limiter: &self.limiter,
+            client: &mut client,
+        };
+        // the error holds the callback, so it must be formatted before reading `client`
+        let web_socket = accept_hdr(stream, callback).map_err(|e| e.to_string());
+        let web_socket = web_socket.map_err(|e| {
+            io::Error::other(anyhow!("could not accept websocket from {}: {}", client, e))
         })?;
 
-        Ok(Socket { web_socket })
+        log::info!("accepted client {}", client);
+        Ok(Socket {
+            web_socket,
+            dns: DnsConfig::default(),
+        })
     }
 }
 
-struct AutherizationCallback(String);
+/// What is known about the client during the handshake.
+struct ClientInfo {
+    addr: Option<net::IpAddr>,
+    vhost: Option<String>,
+    username: Option<String>,
+}
 
-impl Callback for AutherizationCallback {
+impl std::fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.addr {
+            Some(addr) => write!(f, "{}", addr)?,
+            None => write!(f, "unknown")?,
+        }
+        if let Some(ref username) = self.username {
+            write!(f, " user {}", username)?;
+        }
+        if let Some(ref vhost) = self.vhost {
+            write!(f, " on {}", vhost)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the original client address recorded by the reverse proxy.
+/// The left most address of `X-Forwarded-For` is the one the first proxy saw.
+fn forwarded_addr(request: &Request) -> Option<net::IpAddr> {
+    let headers = request.headers();
+    let forwarded_for = headers
+        .get("x-forwarded-for")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .and_then(|v| v.trim().parse().ok());
+
+    forwarded_for.or_else(|| {
+        headers
+            .get("x-real-ip")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok())
+    })
+}
+
+struct AutherizationCallback<'a> {
+    users: &'a Users,
+    options: &'a HandshakeOptions,
+    limiter: &'a Mutex<RateLimiter>,
+    client: &'a mut ClientInfo,
+}
+
+impl<'a> Callback for AutherizationCallback<'a> {
     fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
-        let autherization = &request.headers()[http::header::AUTHORIZATION];
-        if autherization != &self.0 {
+        if self.options.trust_forwarded {
+            if let Some(addr) = forwarded_addr(request) {
+                self.client.addr = Some(addr);
+            }
+        }
+        if self.client.vhost.is_none() {
+            self.client.vhost = request
+                .headers()
+                .get(http::header::HOST)
+                .and_then(|v| v.to_str().ok())
+                .and_then(host_name);
+        }
+
+        if request.uri().path() != self.options.path {
+            let resp = Response::builder()
+                .status(http::StatusCode::NOT_FOUND)
+                .body(None)
+                .unwrap();
+            return Err(resp);
+        }
+
+        let mut limiter = self.limiter.lock().unwrap();
+        if let Some(addr) = self.client.addr {
+            if limiter.is_limited(addr) {
+                log::warn!("too many failed attempts from {}", addr);
+                let resp = Response::builder()
+                    .status(http::StatusCode::TOO_MANY_REQUESTS)
+                    .body(None)
+                    .unwrap();
+                return Err(resp);
+            }
+        }
+
+        let missing =
+            self.options.required_headers.iter().find(|(name, value)| {
+                !request.headers().get_all(*name).iter().any(|v| v == *value)
+            });
+        if let Some((name, _)) = missing {
+            log::warn!("required header {} is missing from {}", name, self.client);
+            if let Some(addr) = self.client.addr {
+                limiter.record_failure(addr);
+            }
+            let resp = Response::builder()
+                .status(http::StatusCode::FORBIDDEN)
+                .body(None)
+                .unwrap();
+            return Err(resp);
+        }
+
+        let users = self
+            .client
+            .vhost
+            .as_ref()
+            .and_then(|vhost| self.options.vhost_users.get(&vhost.to_ascii_lowercase()))
+            .unwrap_or(self.users);
+        let username = request
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|v| users.authenticate(v));
+        self.client.username = username.map(|u| u.to_string());
+        if username.is_none() {
+            if let Some(addr) = self.client.addr {
+                limiter.record_failure(addr);
+            }
             let resp = Response::builder()
                 .header(
                     http::header::WWW_AUTHENTICATE,
@@ -166,81 +473,455 @@ impl Callback for AutherizationCallback {
                 .unwrap();
             Err(resp)
         } else {
-            Ok(response)
+            let dns = username
+                .and_then(|u| self.options.user_dns.get(u))
+                .unwrap_or(&self.options.dns);
+            Ok(push_dns(response, dns))
         }
     }
 }
 
+fn push_dns(mut response: Response, dns: &DnsConfig) -> Response {
+    let servers: Vec<String> = dns.servers.iter().map(|s| s.to_string()).collect();
+    for (name, values) in &[
+        (DNS_HEADER, servers),
+        (DNS_SEARCH_HEADER, dns.search.clone()),
+    ] {
+        if values.is_empty() {
+            continue;
+        }
+        match http::HeaderValue::from_str(&values.join(", ")) {
+            Ok(value) => {
+                response.headers_mut().insert(*name, value);
+            }
+            Err(e) => log::warn!("could not push {}: {}", name, e),
+        }
+    }
+    response
+}
+
+/// The DNS settings pushed by `push_dns`, malformed addresses and domains are skipped.
+fn pushed_dns(headers: &http::HeaderMap) -> DnsConfig {
+    let list = |name: &str| -> Vec<String> {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect()
+    };
+    DnsConfig {
+        servers: list(DNS_HEADER)
+            .iter()
+            .filter_map(|s| match s.parse() {
+                Ok(addr) => Some(addr),
+                Err(e) => {
+                    log::warn!("ignore pushed dns {:?}: {}", s, e);
+                    None
+                }
+            })
+            .collect(),
+        search: list(DNS_SEARCH_HEADER)
+            .into_iter()
+            .filter(|domain| {
+                let valid = crate::dns::is_search_domain(domain);
+                if !valid {
+                    log::warn!("ignore pushed search domain {:?}", domain);
+                }
+                valid
+            })
+            .collect(),
+    }
+}
+
+/// Counts failures per address within a sliding window.
+struct RateLimiter {
+    window: Duration,
+    max_failures: usize,
+    failures: HashMap<net::IpAddr, Vec<Instant>>,
+}
+
+impl RateLimiter {
+    fn new(window: Duration, max_failures: usize) -> Self {
+        Self {
+            window,
+            max_failures,
+            failures: HashMap::new(),
+        }
+    }
+
+    fn is_limited(&mut self, addr: net::IpAddr) -> bool {
+        self.expire();
+        self.failures
+            .get(&addr)
+            .map(|f| f.len() >= self.max_failures)
+            .unwrap_or(false)
+    }
+
+    fn record_failure(&mut self, addr: net::IpAddr) {
+        self.failures.entry(addr).or_default().push(Instant::now());
+    }
+
+    fn expire(&mut self) {
+        let window = self.window;
+        self.failures.retain(|_, f| {
+            f.retain(|t| t.elapsed() < window);
+            !f.is_empty()
+        });
+    }
+}
+
+/// The lowercase host of a `Host` header, without the port.
+fn host_name(host: &str) -> Option<String> {
+    let authority = host.parse::<http::uri::Authority>().ok()?;
+    Some(authority.host().to_ascii_lowercase())
+}
+
+/// The WebSocket upgrade request made by the client.
+#[derive(Clone)]
+pub struct UpgradeRequest {
+    /// The value of the `Host` header.
+    pub host: String,
+    /// The request path, e.g. `/ws`.
+    pub path: String,
+    /// Extra headers like `User-Agent` or `Origin`, see `parse_header`.
+    pub headers: http::HeaderMap,
+    pub auth: BasicAuthentication,
+}
+
+impl UpgradeRequest {
+    fn build(&self, scheme: &str) -> io::Result<Request> {
+        // `ws` would be taken for the end of the host
+        if !self.path.starts_with('/') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                anyhow!("ws path must start with '/': {}", self.path),
+            ));
+        }
+        let uri = format!("{}://{}{}", scheme, self.host, self.path);
+        let mut builder = Request::builder()
+            .uri(&uri)
+            .header(http::header::AUTHORIZATION, self.auth.autherization());
+        for (name, value) in &self.headers {
+            builder = builder.header(name, value);
+        }
+        builder.body(()).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                anyhow!("invalid request {}: {}", uri, e),
+            )
+        })
+    }
+}
+
+/// Options of the client side TLS.
+pub struct ClientTlsOptions {
+    /// The name to verify the server certificate against,
+    /// and to send as SNI unless `enable_sni` is false.
+    pub server_name: String,
+    pub enable_sni: bool,
+    pub trust: TrustStore,
+    /// When not empty, one certificate of the server chain must match one of the pins.
+    pub pins: Vec<SpkiPin>,
+    pub policy: TlsPolicy,
+    /// Keeps the TLS sessions in a file to resume them after a restart,
+    /// one cache may be shared by the connectors to several servers.
+    pub session_cache: Option<Arc<FileSessionCache>>,
+}
+
 pub struct TlsTcpConnector {
-    hostname: webpki::DNSName,
+    server_name: webpki::DNSName,
     tls_config: Arc<rustls::ClientConfig>,
-    auth: BasicAuthentication,
+    verifier: Arc<dyn rustls::ServerCertVerifier>,
+    secrets: Option<Arc<SecretLog>>,
+    dialer: Dialer,
+    request: UpgradeRequest,
 }
 
 impl TlsTcpConnector {
-    pub fn new(hostname: &str, ca_cert_path: &str, auth: BasicAuthentication) -> io::Result<Self> {
+    pub fn new(
+        options: ClientTlsOptions,
+        dialer: Dialer,
+        request: UpgradeRequest,
+    ) -> io::Result<Self> {
         let mut tls_config = rustls::ClientConfig::new();
-        let ca_cert_file = fs::File::open(ca_cert_path)?;
-        let mut ca_cert_reader = io::BufReader::new(ca_cert_file);
-        tls_config
-            .root_store
-            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        tls_config.enable_sni = options.enable_sni;
+        options.policy.apply_client(&mut tls_config);
+        options.trust.load(&mut tls_config.root_store)?;
+
+        let verifier: Arc<dyn rustls::ServerCertVerifier> = if options.pins.is_empty() {
+            Arc::new(rustls::WebPKIVerifier::new())
+        } else {
+            Arc::new(PinnedVerifier::new(options.pins))
+        };
         tls_config
-            .root_store
-            .add_pem_file(&mut ca_cert_reader)
-            .or_else(|_e| {
-                Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    anyhow!("could not add ca cert"),
-                ))
-            })?;
+            .dangerous()
+            .set_certificate_verifier(verifier.clone());
+
+        // the session cache of the config is kept across reconnects
+        if let Some(cache) = options.session_cache {
+            if options.policy.session_resumption() {
+                tls_config.session_persistence = cache;
+            }
+        }
+
+        let secrets = secret_log(&options.policy, &mut tls_config.key_log);
 
-        let hostname = webpki::DNSNameRef::try_from_ascii_str(hostname)
-            .or_else(|e| {
-                Err(io::Error::new(
+        let server_name = webpki::DNSNameRef::try_from_ascii_str(&options.server_name)
+            .map_err(|e| {
+                io::Error::new(
                     io::ErrorKind::InvalidData,
-                    anyhow!("invalid hostname: {:?}", e),
-                ))
+                    anyhow!("invalid server name: {:?}", e),
+                )
             })?
             .to_owned();
 
+        // fail early on an invalid host or path
+        request.build("wss")?;
+
         Ok(Self {
-            hostname,
+            server_name,
             tls_config: Arc::new(tls_config),
-            auth,
+            verifier,
+            secrets,
+            dialer,
+            request,
         })
     }
 
-    pub fn connect<A: net::ToSocketAddrs>(
+    /// Connects to `addr`, which is `host:port`.
+    pub fn connect(&self, addr: &str) -> io::Result<Socket<TlsStream<rustls::ClientSession>>> {
+        let tcp_stream = self.dialer.dial(addr)?;
+        self.dialer.handshake(addr, tcp_stream, |tcp_stream| {
+            self.handshake(addr, tcp_stream)
+        })
+    }
+
+    fn handshake(
         &self,
-        addr: A,
-    ) -> io::Result<Socket<rustls::StreamOwned<rustls::ClientSession, net::TcpStream>>> {
-        let mut tcp_stream = net::TcpStream::connect(addr)?;
-        tcp_stream.set_nodelay(true)?;
+        addr: &str,
+        mut tcp_stream: net::TcpStream,
+    ) -> io::Result<Socket<TlsStream<rustls::ClientSession>>> {
+        // a verifier of its own tells whether this handshake verified the certificate,
+        // a probe may run a handshake meanwhile
+        let verifier = Arc::new(CountingVerifier::new(self.verifier.clone()));
+        let mut tls_config = (*self.tls_config).clone();
+        tls_config
+            .dangerous()
+            .set_certificate_verifier(verifier.clone());
+
+        let mut tls_session =
+            rustls::ClientSession::new(&Arc::new(tls_config), self.server_name.as_ref());
+        let records =
+            ktls::complete_handshake(&mut tls_session, &mut tcp_stream, self.secrets.is_some())
+                .map_err(|e| {
+                    if verifier.rejected() == 0 {
+                        return e;
+                    }
+                    io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        Rejected(format!("server certificate rejected: {}", e)),
+                    )
+                })?;
+        tls::log_session(&addr, &tls_session, verifier.verified() == 0);
+
+        let tls_stream = match (records, &self.secrets) {
+            (Some(records), Some(secrets)) => {
+                ktls::offload(tls_session, tcp_stream, &records, secrets, true)?
+            }
+            _ => TlsStream::Rustls(rustls::StreamOwned::new(tls_session, tcp_stream)),
+        };
 
-        let mut tls_session = rustls::ClientSession::new(&self.tls_config, self.hostname.as_ref());
-        tls_session.complete_io(&mut tcp_stream)?;
+        client_handshake(self.request.build("wss")?, tls_stream)
+    }
 
-        let tls_stream = rustls::StreamOwned::new(tls_session, tcp_stream);
+    /// Completes a TLS handshake with `addr` and hangs up, to learn whether the server is
+    /// reachable again without starting a session on it.
+    pub fn probe(&self, addr: &str) -> io::Result<()> {
+        let tcp_stream = self.dialer.dial(addr)?;
+        self.dialer.handshake(addr, tcp_stream, |mut tcp_stream| {
+            let mut tls_session =
+                rustls::ClientSession::new(&self.tls_config, self.server_name.as_ref());
+            tls_session.complete_io(&mut tcp_stream)?;
+            tls_session.send_close_notify();
+            tls_session.write_tls(&mut tcp_stream)?;
+            Ok(())
+        })
+    }
+}
 
-        let uri = format!("wss://{:}/ws", AsRef::<str>::as_ref(&self.hostname));
-        let request = Request::builder()
-            .uri(&uri)
-            .header(http::header::AUTHORIZATION, self.auth.autherization())
-            .body(())
-            .unwrap();
+/// With kernel TLS, wraps `key_log` to learn the secrets to hand to the kernel.
+fn secret_log(policy: &TlsPolicy, key_log: &mut Arc<dyn rustls::KeyLog>) -> Option<Arc<SecretLog>> {
+    if !policy.kernel_tls() {
+        return None;
+    }
+    let secrets = Arc::new(SecretLog::new(key_log.clone()));
+    *key_log = secrets.clone();
+    Some(secrets)
+}
+
+/// Connects with plain WebSocket over TCP, the counterpart of `TcpListener`.
+pub struct TcpConnector {
+    dialer: Dialer,
+    request: UpgradeRequest,
+}
+
+impl TcpConnector {
+    pub fn new(dialer: Dialer, request: UpgradeRequest) -> io::Result<Self> {
+        request.build("ws")?;
+
+        Ok(Self { dialer, request })
+    }
+
+    /// Connects to `addr`, which is `host:port`.
+    pub fn connect(&self, addr: &str) -> io::Result<Socket<net::TcpStream>> {
+        let request = self.request.build("ws")?;
+        let tcp_stream = self.dialer.dial(addr)?;
 
-        let (web_socket, _resp) = client(request, tls_stream).map_err(|e| {
+        self.dialer.handshake(addr, tcp_stream, |tcp_stream| {
+            client_handshake(request, tcp_stream)
+        })
+    }
+
+    /// Connects to `addr` and hangs up, to learn whether the server is reachable again
+    /// without starting a session on it.
+    pub fn probe(&self, addr: &str) -> io::Result<()> {
+        self.dialer.dial(addr).map(drop)
+    }
+}
+
+/// Parses a header in the form of `Name: value`.
+///
+/// Headers managed by the WebSocket handshake itself are rejected,
+/// use the dedicated options for `Host` and `Authorization`.
+pub fn parse_header(s: &str) -> io::Result<(http::header::HeaderName, http::HeaderValue)> {
+    let invalid = |reason: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            anyhow!("invalid header {:?}: {}", s, reason),
+        )
+    };
+
+    let mut parts = s.splitn(2, ':');
+    let name = parts.next().unwrap_or("").trim();
+    let value = parts
+        .next()
+        .ok_or_else(|| invalid("expect `Name: value`".to_string()))?
+        .trim();
+
+    let name = http::header::HeaderName::from_bytes(name.as_bytes())
+        .map_err(|e| invalid(e.to_string()))?;
+    let value = http::HeaderValue::from_str(value).map_err(|e| invalid(e.to_string()))?;
+
+    let reserved = [
+        http::header::HOST,
+        http::header::AUTHORIZATION,
+        http::header::CONNECTION,
+        http::header::UPGRADE,
+    ];
+    if reserved.contains(&name) || name.as_str().starts_with("sec-websocket-") {
+        return Err(invalid("header is managed by the handshake".to_string()));
+    }
+
+    Ok((name, value))
+}
+
+/// Parses headers from a file, one `Name: value` per line.
+/// Empty lines and lines starting with `#` are ignored.
+pub fn load_headers(filename: &str) -> io::Result<http::HeaderMap> {
+    let content = fs::read_to_string(filename)?;
+    let mut headers = http::HeaderMap::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = parse_header(line)?;
+        headers.append(name, value);
+    }
+
+    Ok(headers)
+}
+
+fn client_handshake<S: io::Read + io::Write>(request: Request, stream: S) -> io::Result<Socket<S>> {
+    let (web_socket, resp) = client(request, stream).map_err(|e| match e {
+        HandshakeError::Failure(Error::Http(status))
+            if status == http::StatusCode::UNAUTHORIZED
+                || status == http::StatusCode::FORBIDDEN =>
+        {
             io::Error::new(
-                io::ErrorKind::Other,
-                anyhow!("could not connect websocket: {}", e),
+                io::ErrorKind::PermissionDenied,
+                Rejected(format!("server rejected the upgrade request: {}", status)),
             )
-        })?;
+        }
+        // only a socket timeout interrupts a blocking handshake
+        HandshakeError::Interrupted(_) => io::ErrorKind::WouldBlock.into(),
+        _ => io::Error::other(anyhow!("could not connect websocket: {}", e)),
+    })?;
+
+    Ok(Socket {
+        web_socket,
+        dns: pushed_dns(resp.headers()),
+    })
+}
 
-        Ok(Socket { web_socket })
+/// The credentials a server accepts.
+pub struct Users {
+    /// Pairs of username and the expected `Authorization` header.
+    autherizations: Vec<(String, String)>,
+}
+
+impl Users {
+    pub fn new(users: Vec<BasicAuthentication>) -> Self {
+        let autherizations = users
+            .into_iter()
+            .map(|u| {
+                let autherization = u.autherization();
+                (u.username, autherization)
+            })
+            .collect();
+        Self { autherizations }
+    }
+
+    /// Loads users from a file, one `username:password` per line.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn load(filename: &str) -> io::Result<Self> {
+        let content = fs::read_to_string(filename)?;
+        let mut users = Vec::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, ':');
+            let username = parts.next().unwrap_or("");
+            let password = parts.next().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    anyhow!("{}:{}: expect username:password", filename, i + 1),
+                )
+            })?;
+            users.push(BasicAuthentication {
+                username: username.to_string(),
+                password: password.to_string(),
+            });
+        }
+
+        Ok(Self::new(users))
+    }
+
+    /// Returns the username if the `Authorization` header matches one of the users.
+    fn authenticate(&self, autherization: &http::HeaderValue) -> Option<&str> {
+        self.autherizations
+            .iter()
+            .find(|(_, expected)| autherization == expected)
+            .map(|(username, _)| username.as_str())
     }
 }
 
+#[derive(Clone)]
 pub struct BasicAuthentication {
     pub username: String,
     pub password: String,
@@ -253,36 +934,22 @@ impl BasicAuthentication {
     }
 }
 
-fn load_private_keys(filename: &str) -> io::Result<Vec<rustls::PrivateKey>> {
-    let keyfile = fs::File::open(filename)?;
-    let mut reader = io::BufReader::new(keyfile);
-    let mut keys = rustls::internal::pemfile::pkcs8_private_keys(&mut reader).or_else(|_e| {
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            anyhow!("file contains invalid private key"),
-        ))
-    })?;
-
-    if keys.is_empty() {
-        reader.seek(io::SeekFrom::Start(0))?;
-        keys = rustls::internal::pemfile::rsa_private_keys(&mut reader).or_else(|_e| {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                anyhow!("file contains invalid rsa private key"),
-            ))
-        })?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_names() {
+        for &(host, expected) in &[
+            ("tunnel.example.com", Some("tunnel.example.com")),
+            ("Tunnel.Example.COM:8443", Some("tunnel.example.com")),
+            ("192.0.2.1:443", Some("192.0.2.1")),
+            ("[2001:db8::1]", Some("[2001:db8::1]")),
+            ("[2001:DB8::1]:443", Some("[2001:db8::1]")),
+            ("", None),
+            ("bad host", None),
+        ] {
+            assert_eq!(host_name(host).as_deref(), expected, "{:?}", host);
+        }
     }
-
-    Ok(keys)
-}
-
-fn load_certs(filename: &str) -> io::Result<Vec<rustls::Certificate>> {
-    let certfile = fs::File::open(filename)?;
-    let mut reader = io::BufReader::new(certfile);
-    rustls::internal::pemfile::certs(&mut reader).or_else(|_e| {
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            anyhow!("file contains invalid cert"),
-        ))
-    })
 }